@@ -0,0 +1,382 @@
+use crate::gtfs::ScheduleOnlyStops;
+use crate::observation::StaleVehicles;
+use anyhow::{Context, Result};
+use chrono_tz::Tz;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::collections::HashSet;
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::{LazyLock, Mutex, RwLock, RwLockReadGuard};
+use std::time::Duration;
+
+static REGISTRY: LazyLock<RwLock<AgencyRegistry>> =
+    LazyLock::new(|| RwLock::new(AgencyRegistry::builtin()));
+
+static INTERNED: LazyLock<Mutex<HashSet<&'static str>>> =
+    LazyLock::new(|| Mutex::new(FeedId::BUILTIN.iter().map(|feed| feed.as_str()).collect()));
+
+/// Identifies an agency by the prefix Unwire puts in front of its ids (`DART:1234`).
+///
+/// Cheap to copy. Ids outside the built-in set are interned when an [`Agency`] naming them is
+/// loaded, so only configured agencies ever allocate; look other ids up with [`FeedId::find`].
+#[derive(Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct FeedId(&'static str);
+
+impl FeedId {
+    pub const DART: FeedId = FeedId("DART");
+    pub const CCRTA: FeedId = FeedId("CCRTA");
+    pub const MCALLEN: FeedId = FeedId("MCALLEN");
+    pub const FWTA: FeedId = FeedId("FWTA");
+
+    const BUILTIN: [FeedId; 4] = [FeedId::DART, FeedId::CCRTA, FeedId::MCALLEN, FeedId::FWTA];
+
+    #[deprecated(note = "use `FeedId::DART`")]
+    #[allow(non_upper_case_globals)]
+    pub const Dart: FeedId = FeedId::DART;
+    #[deprecated(note = "use `FeedId::CCRTA`")]
+    #[allow(non_upper_case_globals)]
+    pub const Ccrta: FeedId = FeedId::CCRTA;
+    #[deprecated(note = "use `FeedId::MCALLEN`")]
+    #[allow(non_upper_case_globals)]
+    pub const Mcallen: FeedId = FeedId::MCALLEN;
+    #[deprecated(note = "use `FeedId::FWTA`")]
+    #[allow(non_upper_case_globals)]
+    pub const Fwta: FeedId = FeedId::FWTA;
+
+    /// The four agencies the crate has always supported, as `all()` returned before agencies
+    /// became configurable.
+    pub const fn builtin() -> [FeedId; 4] {
+        FeedId::BUILTIN
+    }
+
+    /// The feed with this prefix, if it is built in or an agency has been loaded for it.
+    pub fn find(prefix: &str) -> Option<FeedId> {
+        INTERNED
+            .lock()
+            .unwrap()
+            .get(prefix)
+            .map(|&existing| FeedId(existing))
+    }
+
+    /// Interns `prefix` for the lifetime of the process; only for agency definitions.
+    pub(crate) fn intern(prefix: &str) -> FeedId {
+        let mut interned = INTERNED.lock().unwrap();
+        if let Some(existing) = interned.get(prefix) {
+            return FeedId(existing);
+        }
+        let leaked: &'static str = Box::leak(prefix.to_string().into_boxed_str());
+        interned.insert(leaked);
+        FeedId(leaked)
+    }
+
+    pub const fn as_str(&self) -> &'static str {
+        self.0
+    }
+
+    /// Every feed currently in the agency registry, including configured agencies. Use
+    /// [`FeedId::builtin`] where a fixed-size array is needed.
+    pub fn all() -> Vec<FeedId> {
+        registry().iter().map(|agency| agency.feed_id()).collect()
+    }
+}
+
+impl fmt::Debug for FeedId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "FeedId({})", self.0)
+    }
+}
+
+impl fmt::Display for FeedId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.0)
+    }
+}
+
+impl Serialize for FeedId {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(self.0)
+    }
+}
+
+fn deserialize_feed_id<'de, D: Deserializer<'de>>(deserializer: D) -> Result<FeedId, D::Error> {
+    let prefix = String::deserialize(deserializer)?;
+    Ok(FeedId::intern(&prefix))
+}
+
+/// An agency served through an Unwire ticketing tenant.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Agency {
+    /// Id prefix used by Unwire, e.g. `DART`.
+    #[serde(deserialize_with = "deserialize_feed_id")]
+    pub id: FeedId,
+    pub display_name: String,
+    pub tenant_id: String,
+    /// IANA timezone the agency's schedules are expressed in.
+    pub timezone: String,
+    /// Overrides the `Referer` header of the tenant's web app.
+    #[serde(default)]
+    pub referer: Option<String>,
+    /// Overrides the `Origin` header of the tenant's web app.
+    #[serde(default)]
+    pub origin: Option<String>,
+    #[serde(default)]
+    pub schedule_only_stops: ScheduleOnlyStops,
+    /// Local hour before which departures belong to the previous service day (`25:10:00`).
+    #[serde(default = "default_service_day_cutoff_hour")]
+    pub service_day_cutoff_hour: u32,
+    /// Local static GTFS zip used to validate and enrich realtime ids.
+    #[serde(default)]
+    pub static_gtfs_path: Option<PathBuf>,
+    /// Seconds a vehicle may sit without moving before it is treated as stale.
+    #[serde(default = "default_stale_after_secs")]
+    pub stale_after_secs: u64,
+    #[serde(default)]
+    pub stale_vehicles: StaleVehicles,
+}
+
+fn default_service_day_cutoff_hour() -> u32 {
+    3
+}
+
+fn default_stale_after_secs() -> u64 {
+    30 * 60
+}
+
+impl Agency {
+    pub fn feed_id(&self) -> FeedId {
+        self.id
+    }
+
+    pub fn stale_after(&self) -> Duration {
+        Duration::from_secs(self.stale_after_secs)
+    }
+
+    pub fn tz(&self) -> Result<Tz> {
+        self.timezone
+            .parse()
+            .map_err(|e| anyhow::anyhow!("invalid timezone for {}: {}", self.id, e))
+    }
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct AgencyRegistry {
+    #[serde(default)]
+    agencies: Vec<Agency>,
+    /// Agencies added by [`register`], as opposed to built in.
+    #[serde(skip)]
+    registered: HashSet<FeedId>,
+}
+
+impl AgencyRegistry {
+    /// The agencies on tenant 205 that the crate has always supported.
+    pub fn builtin() -> Self {
+        let agency = |id: FeedId, display_name: &str| Agency {
+            id,
+            display_name: display_name.to_string(),
+            tenant_id: "205".to_string(),
+            timezone: "America/Chicago".to_string(),
+            referer: None,
+            origin: None,
+            schedule_only_stops: ScheduleOnlyStops::default(),
+            service_day_cutoff_hour: default_service_day_cutoff_hour(),
+            static_gtfs_path: None,
+            stale_after_secs: default_stale_after_secs(),
+            stale_vehicles: StaleVehicles::default(),
+        };
+
+        Self {
+            agencies: vec![
+                Agency {
+                    referer: Some("https://dart.mygopass.org/".to_string()),
+                    origin: Some("https://dart.mygopass.org".to_string()),
+                    ..agency(FeedId::DART, "Denton County Transportation Authority")
+                },
+                agency(
+                    FeedId::CCRTA,
+                    "Corpus Christi Regional Transportation Authority",
+                ),
+                agency(FeedId::MCALLEN, "McAllen Metro"),
+                agency(FeedId::FWTA, "Trinity Metro"),
+            ],
+            registered: HashSet::new(),
+        }
+    }
+
+    /// Reads `[[agencies]]` entries from a TOML file, or a JSON file with an `agencies` array.
+    pub fn from_file(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        let text = fs::read_to_string(path)
+            .with_context(|| format!("failed to read agencies {}", path.display()))?;
+
+        let is_json = path
+            .extension()
+            .is_some_and(|ext| ext.eq_ignore_ascii_case("json"));
+
+        if is_json {
+            serde_json::from_str(&text)
+                .with_context(|| format!("failed to parse agencies {}", path.display()))
+        } else {
+            toml::from_str(&text)
+                .with_context(|| format!("failed to parse agencies {}", path.display()))
+        }
+    }
+
+    /// Adds an agency, replacing any existing entry with the same id.
+    pub fn insert(&mut self, agency: Agency) {
+        match self.agencies.iter_mut().find(|a| a.id == agency.id) {
+            Some(existing) => *existing = agency,
+            None => self.agencies.push(agency),
+        }
+    }
+
+    pub fn get(&self, feed: FeedId) -> Option<&Agency> {
+        self.agencies.iter().find(|a| a.id == feed)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Agency> {
+        self.agencies.iter()
+    }
+
+    /// Finds the agency whose prefix, followed by `:` or `-`, starts `id`.
+    pub fn feed_for_id(&self, id: &str) -> Option<FeedId> {
+        self.agencies
+            .iter()
+            .find(|agency| split_prefix(id, agency.id).is_some())
+            .map(|agency| agency.id)
+    }
+
+    pub fn strip_prefix(&self, id: &str) -> String {
+        self.agencies
+            .iter()
+            .find_map(|agency| split_prefix(id, agency.id))
+            .unwrap_or(id)
+            .to_string()
+    }
+}
+
+fn split_prefix(id: &str, feed: FeedId) -> Option<&str> {
+    let rest = id.strip_prefix(feed.as_str())?;
+    rest.strip_prefix(':').or_else(|| rest.strip_prefix('-'))
+}
+
+/// The process-wide registry used for id prefix handling.
+pub fn registry() -> RwLockReadGuard<'static, AgencyRegistry> {
+    REGISTRY.read().unwrap()
+}
+
+/// Adds agencies to the process-wide registry.
+///
+/// A built-in agency may be overridden, but an agency already registered (e.g. by another
+/// fetcher's config) can only be registered again with identical settings. Agencies with an
+/// unknown timezone are refused too. On error nothing is changed.
+pub fn register(agencies: impl IntoIterator<Item = Agency>) -> Result<()> {
+    let agencies: Vec<Agency> = agencies.into_iter().collect();
+    for agency in &agencies {
+        agency.tz()?;
+    }
+
+    let mut registry = REGISTRY.write().unwrap();
+    for agency in &agencies {
+        if registry.registered.contains(&agency.id)
+            && registry
+                .get(agency.id)
+                .is_some_and(|existing| existing != agency)
+        {
+            anyhow::bail!(
+                "agency {} is already registered with different settings",
+                agency.id
+            );
+        }
+    }
+    for agency in agencies {
+        registry.registered.insert(agency.id);
+        registry.insert(agency);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn agency(id: &str) -> Agency {
+        Agency {
+            id: FeedId::intern(id),
+            ..registry().get(FeedId::DART).unwrap().clone()
+        }
+    }
+
+    #[test]
+    fn register_refuses_conflicting_settings() {
+        let original = agency("CONFLICT");
+        register([original.clone()]).unwrap();
+        // Registering the same settings again, e.g. from a second fetcher, is fine.
+        register([original.clone()]).unwrap();
+
+        let changed = Agency {
+            tenant_id: "999".to_string(),
+            ..original.clone()
+        };
+        assert!(register([changed]).is_err());
+        assert_eq!(registry().get(original.id), Some(&original));
+    }
+
+    #[test]
+    fn matches_prefixes_followed_by_a_separator() {
+        let mut registry = AgencyRegistry::builtin();
+        registry.insert(agency("DARTX"));
+
+        assert_eq!(registry.feed_for_id("DART:1234"), Some(FeedId::DART));
+        assert_eq!(registry.feed_for_id("DART-42"), Some(FeedId::DART));
+        assert_eq!(
+            registry.feed_for_id("DARTX:1"),
+            Some(FeedId::intern("DARTX"))
+        );
+        assert_eq!(registry.feed_for_id("DART"), None);
+        assert_eq!(registry.feed_for_id("OTHER:1"), None);
+
+        assert_eq!(registry.strip_prefix("CCRTA:77"), "77");
+        assert_eq!(registry.strip_prefix("FWTA-9"), "9");
+        assert_eq!(registry.strip_prefix("DARTX:1"), "1");
+        assert_eq!(registry.strip_prefix("OTHER:1"), "OTHER:1");
+    }
+
+    #[test]
+    fn reads_agencies_from_toml() {
+        let path = std::env::temp_dir().join(format!("agencies-{}.toml", std::process::id()));
+        fs::write(
+            &path,
+            r#"
+            [[agencies]]
+            id = "METRO"
+            display_name = "Metro"
+            tenant_id = "300"
+            timezone = "America/Denver"
+            "#,
+        )
+        .unwrap();
+
+        let registry = AgencyRegistry::from_file(&path);
+        let _ = fs::remove_file(&path);
+
+        let agency = registry.unwrap().iter().next().cloned().unwrap();
+        assert_eq!(agency.id.as_str(), "METRO");
+        assert_eq!(agency.tz().unwrap(), chrono_tz::America::Denver);
+        assert_eq!(agency.service_day_cutoff_hour, 3);
+    }
+
+    #[test]
+    fn register_refuses_an_unknown_timezone() {
+        let agency = Agency {
+            timezone: "America/Nowhere".to_string(),
+            ..agency("BADTZ")
+        };
+
+        let error = register([agency.clone(), self::agency("GOODTZ")]).unwrap_err();
+
+        assert!(error.to_string().contains("BADTZ"), "{error:#}");
+        assert!(registry().get(agency.id).is_none());
+        assert!(registry().get(FeedId::intern("GOODTZ")).is_none());
+    }
+}
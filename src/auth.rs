@@ -1,19 +1,19 @@
+use crate::config::UnwireConfig;
+use crate::credentials::{CredentialStore, FileCredentialStore, StoredCredentials};
+use crate::transport::{HttpRequest, HttpTransport, ReqwestTransport};
 use anyhow::{Context, Result};
 use hmac::{Hmac, Mac};
 use rand::rngs::OsRng;
-use reqwest::Client;
-use rsa::{PaddingScheme, RsaPrivateKey, RsaPublicKey, pkcs8::EncodePublicKey};
+use rsa::pkcs8::{DecodePrivateKey, EncodePrivateKey, EncodePublicKey, LineEnding};
+use rsa::{PaddingScheme, RsaPrivateKey, RsaPublicKey};
 use serde::{Deserialize, Serialize};
 use sha2::{Digest, Sha256};
 use std::fs::OpenOptions;
 use std::io::Write;
+use std::sync::Arc;
 use std::time::{SystemTime, UNIX_EPOCH};
 
-const DEVELOPER_KEY: &str = "057e903f-48b3-4461-b9fc-39d14ca829ce";
-const DEVELOPER_SECRET: &str = "35a425ad968fbe5e4ec8364e8e500420";
-const BASE_URL: &str = "https://ssge-ticketing.us.unwire.com/api-gateway";
 const APP_INSTANCE_URL: &str = "/v1/appinstance";
-const TENANT_ID: &str = "205";
 
 #[derive(Debug, Serialize, Deserialize)]
 struct AppInstanceRequest {
@@ -43,13 +43,21 @@ struct AppInstanceResponse {
 
 #[derive(Clone)]
 pub struct Authenticator {
-    client: Client,
+    transport: Arc<dyn HttpTransport>,
+    config: Arc<UnwireConfig>,
     private_key: RsaPrivateKey,
     decrypted_secret: Option<String>,
     instance_id: Option<String>,
+    store: Option<Arc<dyn CredentialStore>>,
+    from_cache: bool,
+    generation: u64,
 }
 
 fn log_debug(msg: &str) {
+    // Tests run from the repo root; keep them from leaving a debug.log behind.
+    if cfg!(test) {
+        return;
+    }
     if let Ok(mut file) = OpenOptions::new()
         .create(true)
         .append(true)
@@ -60,16 +68,112 @@ fn log_debug(msg: &str) {
 }
 
 impl Authenticator {
-    pub fn new() -> Result<Self> {
+    /// Creates an authenticator backed by the file store named in `config.credentials_path`.
+    pub fn new(config: Arc<UnwireConfig>) -> Result<Self> {
+        let store = config
+            .credentials_path
+            .as_ref()
+            .map(|path| Arc::new(FileCredentialStore::new(path)) as Arc<dyn CredentialStore>);
+        Self::with_store(config, store)
+    }
+
+    /// Creates an authenticator that reuses credentials from `store` when present.
+    ///
+    /// Passing `None` disables persistence, so every instance registers from scratch.
+    pub fn with_store(
+        config: Arc<UnwireConfig>,
+        store: Option<Arc<dyn CredentialStore>>,
+    ) -> Result<Self> {
+        let cached = store
+            .as_deref()
+            .and_then(|store| load_cached_credentials(store, &config.tenant_id));
+        if let Some(cached) = cached {
+            match RsaPrivateKey::from_pkcs8_pem(&cached.private_key_pem) {
+                Ok(private_key) => {
+                    log_debug(&format!(
+                        "Loaded cached instance ID: {}",
+                        cached.instance_id
+                    ));
+                    return Ok(Self {
+                        transport: Arc::new(ReqwestTransport::with_timeouts(&config.timeouts)?),
+                        config,
+                        private_key,
+                        decrypted_secret: Some(cached.decrypted_secret),
+                        instance_id: Some(cached.instance_id),
+                        store,
+                        from_cache: true,
+                        generation: 0,
+                    });
+                }
+                Err(e) => eprintln!("Ignoring cached credentials with invalid key: {}", e),
+            }
+        }
+
         let mut rng = OsRng;
         let bits = 1024;
         let private_key = RsaPrivateKey::new(&mut rng, bits).context("failed to generate a key")?;
 
         Ok(Self {
-            client: Client::new(),
+            transport: Arc::new(ReqwestTransport::with_timeouts(&config.timeouts)?),
+            config,
             private_key,
             decrypted_secret: None,
             instance_id: None,
+            store,
+            from_cache: false,
+            generation: 0,
+        })
+    }
+
+    /// Sends registration requests through `transport` instead of the network.
+    pub fn with_transport(mut self, transport: Arc<dyn HttpTransport>) -> Self {
+        self.transport = transport;
+        self
+    }
+
+    pub fn is_registered(&self) -> bool {
+        self.decrypted_secret.is_some() && self.instance_id.is_some()
+    }
+
+    /// Whether the current credentials were loaded from the store rather than freshly registered.
+    pub fn is_from_cache(&self) -> bool {
+        self.from_cache
+    }
+
+    /// Incremented on every successful registration, so callers can tell whether the
+    /// credentials they signed with have since been replaced.
+    pub fn generation(&self) -> u64 {
+        self.generation
+    }
+
+    /// Removes any persisted credentials so the next authenticator registers again.
+    pub fn discard_cached(&self) -> Result<()> {
+        match &self.store {
+            Some(store) => store.clear(),
+            None => Ok(()),
+        }
+    }
+
+    fn persist(&self) -> Result<()> {
+        let Some(store) = &self.store else {
+            return Ok(());
+        };
+        let (Some(decrypted_secret), Some(instance_id)) =
+            (&self.decrypted_secret, &self.instance_id)
+        else {
+            return Ok(());
+        };
+
+        let private_key_pem = self
+            .private_key
+            .to_pkcs8_pem(LineEnding::LF)
+            .context("failed to encode private key")?;
+
+        store.save(&StoredCredentials {
+            tenant_id: self.config.tenant_id.clone(),
+            private_key_pem: private_key_pem.to_string(),
+            instance_id: instance_id.clone(),
+            decrypted_secret: decrypted_secret.clone(),
         })
     }
 
@@ -82,38 +186,40 @@ impl Authenticator {
         let public_key_b64 = base64::encode(&public_key_der);
 
         let req_body = AppInstanceRequest {
-            tenant_id: TENANT_ID.to_string(),
-            os_type: "Android".to_string(),
-            hardware_id: "1234".to_string(),
+            tenant_id: self.config.tenant_id.clone(),
+            os_type: self.config.os_type.clone(),
+            hardware_id: self.config.hardware_id.clone(),
             public_key: public_key_b64.clone(),
         };
 
         type HmacSha256 = Hmac<Sha256>;
-        let mut mac = HmacSha256::new_from_slice(DEVELOPER_SECRET.as_bytes())
+        let mut mac = HmacSha256::new_from_slice(self.config.developer_secret.as_bytes())
             .expect("HMAC can take key of any size");
         mac.update(req_body.public_key.as_bytes());
         let signature = base64::encode(mac.finalize().into_bytes());
-        let api_developer_key = format!("{}:{}", DEVELOPER_KEY, signature);
+        let api_developer_key = format!("{}:{}", self.config.developer_key, signature);
 
-        let url = format!("{}{}", BASE_URL, APP_INSTANCE_URL);
-        let resp = self
-            .client
-            .post(&url)
-            .header("api-developer-key", api_developer_key)
+        let request = HttpRequest::post(self.config.url(APP_INSTANCE_URL))
+            .header("api-developer-key", &api_developer_key)
             .header("Content-Type", "application/json")
-            .json(&req_body)
-            .send()
-            .await
-            .context("failed to send registration request")?;
+            .json(&req_body)?;
+        let resp = self
+            .config
+            .retry
+            .send("registration", || async {
+                self.transport
+                    .send(request.clone())
+                    .await
+                    .context("failed to send registration request")
+            })
+            .await?;
 
         if !resp.status().is_success() {
-            let status = resp.status();
-            let text = resp.text().await.unwrap_or_default();
-            anyhow::bail!("Registration failed: {} - {}", status, text);
+            anyhow::bail!("Registration failed: {} - {}", resp.status(), resp.text());
         }
 
         let resp_json: AppInstanceResponseWrapper =
-            resp.json().await.context("failed to parse response")?;
+            resp.json().context("failed to parse response")?;
         let app_instance = resp_json.app_instance;
 
         let encrypted_bytes = base64::decode(&app_instance.encrypted_secret)
@@ -133,6 +239,12 @@ impl Authenticator {
 
         self.decrypted_secret = Some(decrypted_secret);
         self.instance_id = Some(app_instance.instance_id);
+        self.from_cache = false;
+        self.generation += 1;
+
+        if let Err(e) = self.persist() {
+            eprintln!("Failed to persist credentials: {:#}", e);
+        }
 
         Ok(())
     }
@@ -215,3 +327,17 @@ impl Authenticator {
         ])
     }
 }
+
+fn load_cached_credentials(
+    store: &dyn CredentialStore,
+    tenant_id: &str,
+) -> Option<StoredCredentials> {
+    match store.load() {
+        Ok(Some(cached)) if cached.tenant_id == tenant_id => Some(cached),
+        Ok(_) => None,
+        Err(e) => {
+            eprintln!("Failed to load cached credentials: {:#}", e);
+            None
+        }
+    }
+}
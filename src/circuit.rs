@@ -0,0 +1,316 @@
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::sync::Mutex;
+use std::time::{Duration, Instant, SystemTime};
+
+/// When a gateway endpoint is given up on, and for how long.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct CircuitBreakerConfig {
+    /// Consecutive failures that open the circuit.
+    pub failure_threshold: u32,
+    /// How long an open circuit rejects requests before letting a probe through.
+    pub open_secs: u64,
+}
+
+impl Default for CircuitBreakerConfig {
+    fn default() -> Self {
+        Self {
+            failure_threshold: 5,
+            open_secs: 30,
+        }
+    }
+}
+
+/// The gateway endpoints that get a breaker each.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Endpoint {
+    Snapshot,
+    Timetable,
+    AppInstance,
+}
+
+impl Endpoint {
+    pub const ALL: [Endpoint; 3] = [
+        Endpoint::Snapshot,
+        Endpoint::Timetable,
+        Endpoint::AppInstance,
+    ];
+}
+
+impl fmt::Display for Endpoint {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Endpoint::Snapshot => "snapshot",
+            Endpoint::Timetable => "timetable",
+            Endpoint::AppInstance => "appinstance",
+        };
+        f.write_str(name)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CircuitState {
+    Closed,
+    Open,
+    HalfOpen,
+}
+
+/// Point-in-time view of a breaker, for metrics.
+#[derive(Debug, Clone, Serialize)]
+pub struct CircuitStatus {
+    pub endpoint: Endpoint,
+    pub state: CircuitState,
+    pub consecutive_failures: u32,
+    /// Times the circuit has opened since startup.
+    pub times_opened: u64,
+    /// Requests turned away without reaching the gateway since startup.
+    pub rejected: u64,
+    pub last_opened_at: Option<SystemTime>,
+}
+
+/// Returned instead of sending a request while the circuit is open.
+#[derive(Debug)]
+pub struct CircuitOpen {
+    pub endpoint: Endpoint,
+}
+
+impl fmt::Display for CircuitOpen {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "circuit open for {} requests", self.endpoint)
+    }
+}
+
+impl std::error::Error for CircuitOpen {}
+
+#[derive(Debug)]
+struct BreakerState {
+    state: CircuitState,
+    consecutive_failures: u32,
+    open_until: Option<Instant>,
+    probe_in_flight: bool,
+    times_opened: u64,
+    rejected: u64,
+    last_opened_at: Option<SystemTime>,
+}
+
+/// Closed / open / half-open breaker for one endpoint.
+///
+/// While open every request fails fast with [`CircuitOpen`]; once `open_secs` pass a single
+/// probe is let through, and its outcome closes or re-opens the circuit.
+#[derive(Debug)]
+pub struct CircuitBreaker {
+    endpoint: Endpoint,
+    config: CircuitBreakerConfig,
+    state: Mutex<BreakerState>,
+}
+
+impl CircuitBreaker {
+    pub fn new(endpoint: Endpoint, config: CircuitBreakerConfig) -> Self {
+        Self {
+            endpoint,
+            config,
+            state: Mutex::new(BreakerState {
+                state: CircuitState::Closed,
+                consecutive_failures: 0,
+                open_until: None,
+                probe_in_flight: false,
+                times_opened: 0,
+                rejected: 0,
+                last_opened_at: None,
+            }),
+        }
+    }
+
+    /// Asks to send a request; fails fast while the circuit is open.
+    pub fn allow(&self) -> Result<(), CircuitOpen> {
+        let mut state = self.state.lock().unwrap();
+        if state.state == CircuitState::Open
+            && state
+                .open_until
+                .is_some_and(|until| Instant::now() >= until)
+        {
+            state.state = CircuitState::HalfOpen;
+            state.probe_in_flight = false;
+        }
+
+        let now = Instant::now();
+        let allowed = match state.state {
+            CircuitState::Closed => true,
+            CircuitState::Open => false,
+            // A probe that never reported back (e.g. a cancelled request) is replaced
+            // after another open period.
+            CircuitState::HalfOpen
+                if state.probe_in_flight && state.open_until.is_some_and(|until| now < until) =>
+            {
+                false
+            }
+            CircuitState::HalfOpen => {
+                state.probe_in_flight = true;
+                state.open_until = Some(now + self.open_duration());
+                true
+            }
+        };
+        if allowed {
+            Ok(())
+        } else {
+            state.rejected += 1;
+            Err(CircuitOpen {
+                endpoint: self.endpoint,
+            })
+        }
+    }
+
+    pub fn record_success(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.state = CircuitState::Closed;
+        state.consecutive_failures = 0;
+        state.open_until = None;
+        state.probe_in_flight = false;
+    }
+
+    pub fn record_failure(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.consecutive_failures += 1;
+        let trip = state.state == CircuitState::HalfOpen
+            || state.consecutive_failures >= self.config.failure_threshold.max(1);
+        if trip && state.state != CircuitState::Open {
+            state.state = CircuitState::Open;
+            state.open_until = Some(Instant::now() + self.open_duration());
+            state.probe_in_flight = false;
+            state.times_opened += 1;
+            state.last_opened_at = Some(SystemTime::now());
+            eprintln!(
+                "Circuit opened for {} requests after {} failures",
+                self.endpoint, state.consecutive_failures
+            );
+        }
+    }
+
+    fn open_duration(&self) -> Duration {
+        Duration::from_secs(self.config.open_secs)
+    }
+
+    pub fn status(&self) -> CircuitStatus {
+        let state = self.state.lock().unwrap();
+        CircuitStatus {
+            endpoint: self.endpoint,
+            state: state.state,
+            consecutive_failures: state.consecutive_failures,
+            times_opened: state.times_opened,
+            rejected: state.rejected,
+            last_opened_at: state.last_opened_at,
+        }
+    }
+}
+
+/// One breaker per [`Endpoint`].
+#[derive(Debug)]
+pub struct CircuitBreakers {
+    snapshot: CircuitBreaker,
+    timetable: CircuitBreaker,
+    app_instance: CircuitBreaker,
+}
+
+impl CircuitBreakers {
+    pub fn new(config: &CircuitBreakerConfig) -> Self {
+        Self {
+            snapshot: CircuitBreaker::new(Endpoint::Snapshot, config.clone()),
+            timetable: CircuitBreaker::new(Endpoint::Timetable, config.clone()),
+            app_instance: CircuitBreaker::new(Endpoint::AppInstance, config.clone()),
+        }
+    }
+
+    pub fn get(&self, endpoint: Endpoint) -> &CircuitBreaker {
+        match endpoint {
+            Endpoint::Snapshot => &self.snapshot,
+            Endpoint::Timetable => &self.timetable,
+            Endpoint::AppInstance => &self.app_instance,
+        }
+    }
+
+    pub fn statuses(&self) -> Vec<CircuitStatus> {
+        Endpoint::ALL
+            .iter()
+            .map(|&endpoint| self.get(endpoint).status())
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn breaker(open_secs: u64) -> CircuitBreaker {
+        CircuitBreaker::new(
+            Endpoint::Snapshot,
+            CircuitBreakerConfig {
+                failure_threshold: 2,
+                open_secs,
+            },
+        )
+    }
+
+    #[test]
+    fn opens_after_consecutive_failures_and_rejects() {
+        let breaker = breaker(60);
+        breaker.record_failure();
+        assert!(breaker.allow().is_ok());
+        breaker.record_failure();
+
+        let status = breaker.status();
+        assert_eq!(status.state, CircuitState::Open);
+        assert_eq!(status.times_opened, 1);
+        assert!(status.last_opened_at.is_some());
+
+        let error = breaker.allow().unwrap_err();
+        assert_eq!(error.endpoint, Endpoint::Snapshot);
+        assert_eq!(breaker.status().rejected, 1);
+    }
+
+    #[test]
+    fn success_resets_the_failure_count() {
+        let breaker = breaker(60);
+        breaker.record_failure();
+        breaker.record_success();
+        breaker.record_failure();
+        assert_eq!(breaker.status().state, CircuitState::Closed);
+        assert_eq!(breaker.status().consecutive_failures, 1);
+    }
+
+    #[test]
+    fn half_open_probe_closes_or_reopens() {
+        let breaker = breaker(0);
+        breaker.record_failure();
+        breaker.record_failure();
+        assert_eq!(breaker.status().state, CircuitState::Open);
+
+        // The open period has passed, so one probe goes through.
+        assert!(breaker.allow().is_ok());
+        assert_eq!(breaker.status().state, CircuitState::HalfOpen);
+        breaker.record_failure();
+        assert_eq!(breaker.status().state, CircuitState::Open);
+        assert_eq!(breaker.status().times_opened, 2);
+
+        assert!(breaker.allow().is_ok());
+        breaker.record_success();
+        let status = breaker.status();
+        assert_eq!(status.state, CircuitState::Closed);
+        assert_eq!(status.consecutive_failures, 0);
+    }
+
+    #[test]
+    fn half_open_lets_only_one_probe_through() {
+        let breaker = breaker(60);
+        breaker.record_failure();
+        breaker.record_failure();
+        // Pretend the open period has passed.
+        breaker.state.lock().unwrap().open_until = Some(Instant::now());
+
+        assert!(breaker.allow().is_ok());
+        assert!(breaker.allow().is_err());
+        assert_eq!(breaker.status().state, CircuitState::HalfOpen);
+    }
+}
@@ -1,17 +1,25 @@
 use crate::auth::Authenticator;
+use crate::circuit::{CircuitBreakers, CircuitStatus, Endpoint};
+use crate::config::UnwireConfig;
 use crate::model::{TripUpdateResponse, VehicleSnapshotResponse};
+use crate::throttle::Throttle;
+use crate::transport::{HttpRequest, HttpResponse, HttpTransport, ReqwestTransport};
 use anyhow::{Context, Result};
-use reqwest::Client;
+use reqwest::StatusCode;
 use std::fs::OpenOptions;
 use std::io::Write;
+use std::sync::Arc;
+use tokio::sync::RwLock;
 
-const BASE_URL: &str = "https://ssge-ticketing.us.unwire.com/api-gateway";
-const BASE_PATH: &str = "/api-gateway";
 const VEHICLES_ENDPOINT: &str = "/v3/api/ttools/vehicles/snapshot";
 const TRIPS_ENDPOINT_PREFIX: &str = "/v5/api/ttools/trips/";
 const TRIPS_ENDPOINT_SUFFIX: &str = "/timetable";
 
 fn log_debug(msg: &str) {
+    // Tests run from the repo root; keep them from leaving a debug.log behind.
+    if cfg!(test) {
+        return;
+    }
     if let Ok(mut file) = OpenOptions::new()
         .create(true)
         .append(true)
@@ -21,53 +29,99 @@ fn log_debug(msg: &str) {
     }
 }
 
+/// Only an explicit 401/403 means the app instance was refused. Other 4xx bodies can
+/// mention words like "expired" for unrelated reasons (e.g. a finished trip), and
+/// re-registering on those would spam the gateway with new app instances.
+fn is_auth_failure(status: StatusCode) -> bool {
+    matches!(status, StatusCode::UNAUTHORIZED | StatusCode::FORBIDDEN)
+}
+
+fn is_gateway_failure(status: StatusCode) -> bool {
+    status.is_server_error() || status == StatusCode::TOO_MANY_REQUESTS
+}
+
+/// Clones share one [`Authenticator`], so a re-registration triggered by any clone
+/// is picked up by all of them. They also share one [`Throttle`] and one set of
+/// [`CircuitBreakers`] and [`HttpTransport`].
 #[derive(Clone)]
 pub struct UnwireClient {
-    client: Client,
-    authenticator: Authenticator,
+    transport: Arc<dyn HttpTransport>,
+    config: Arc<UnwireConfig>,
+    authenticator: Arc<RwLock<Authenticator>>,
+    throttle: Arc<Throttle>,
+    breakers: Arc<CircuitBreakers>,
 }
 
 impl UnwireClient {
+    /// Builds a client from [`UnwireConfig::load`], i.e. the `UNWIRE_CONFIG` file and env vars.
     pub async fn new() -> Result<Self> {
-        let mut authenticator = Authenticator::new()?;
-        authenticator
-            .register()
-            .await
-            .context("failed to register app instance")?;
+        Self::with_config(UnwireConfig::load()?).await
+    }
+
+    pub async fn with_config(config: UnwireConfig) -> Result<Self> {
+        let transport = ReqwestTransport::with_timeouts(&config.timeouts)?;
+        Self::with_transport(config, Arc::new(transport)).await
+    }
+
+    /// Like [`UnwireClient::with_config`], sending every request, registration included,
+    /// through `transport`.
+    pub async fn with_transport(
+        config: UnwireConfig,
+        transport: Arc<dyn HttpTransport>,
+    ) -> Result<Self> {
+        let breakers = Arc::new(CircuitBreakers::new(&config.circuit_breaker));
+        Self::with_breakers(config, transport, breakers).await
+    }
+
+    /// Like [`UnwireClient::with_transport`], with breakers owned by the caller, so a failed
+    /// registration still counts against the app instance circuit on the next attempt.
+    pub(crate) async fn with_breakers(
+        config: UnwireConfig,
+        transport: Arc<dyn HttpTransport>,
+        breakers: Arc<CircuitBreakers>,
+    ) -> Result<Self> {
+        let config = Arc::new(config);
+        let throttle = Arc::new(Throttle::new(&config.throttle));
+        let mut authenticator =
+            Authenticator::new(config.clone())?.with_transport(transport.clone());
+        if !authenticator.is_registered() {
+            register_guarded(&mut authenticator, &breakers, &throttle)
+                .await
+                .context("failed to register app instance")?;
+        }
 
         Ok(Self {
-            client: Client::new(),
-            authenticator,
+            transport,
+            throttle,
+            breakers,
+            config,
+            authenticator: Arc::new(RwLock::new(authenticator)),
         })
     }
 
-    pub async fn fetch_vehicles(&self) -> Result<VehicleSnapshotResponse> {
-        let full_path = format!("{}{}", BASE_PATH, VEHICLES_ENDPOINT);
-        let headers = self
-            .authenticator
-            .get_auth_headers("GET", &full_path, None, None)?;
-
-        let url = format!("{}{}", BASE_URL, VEHICLES_ENDPOINT);
-        let mut req = self.client.get(&url);
+    pub fn config(&self) -> &UnwireConfig {
+        &self.config
+    }
 
-        for (k, v) in headers {
-            req = req.header(k, v);
-        }
+    pub fn transport(&self) -> &Arc<dyn HttpTransport> {
+        &self.transport
+    }
 
-        req = self.add_common_headers(req);
+    pub fn throttle(&self) -> &Throttle {
+        &self.throttle
+    }
 
-        let resp = req.send().await.context("failed to send vehicle request")?;
+    pub fn circuit_statuses(&self) -> Vec<CircuitStatus> {
+        self.breakers.statuses()
+    }
 
-        if !resp.status().is_success() {
-            let status = resp.status();
-            let text = resp.text().await.unwrap_or_default();
-            anyhow::bail!("Vehicle fetch failed: {} - {}", status, text);
-        }
+    pub async fn fetch_vehicles(&self) -> Result<VehicleSnapshotResponse> {
+        let resp = self
+            .get_signed(Endpoint::Snapshot, VEHICLES_ENDPOINT, "vehicle")
+            .await?;
 
-        let snapshot: VehicleSnapshotResponse = resp
-            .json()
-            .await
-            .context("failed to parse vehicle response")?;
+        let snapshot: VehicleSnapshotResponse =
+            resp.json().context("failed to parse vehicle response")?;
 
         Ok(snapshot)
     }
@@ -78,14 +132,91 @@ impl UnwireClient {
             "{}{}{}",
             TRIPS_ENDPOINT_PREFIX, trip_id, TRIPS_ENDPOINT_SUFFIX
         );
-        let full_path = format!("{}{}", BASE_PATH, endpoint);
 
-        let headers = self
-            .authenticator
-            .get_auth_headers("GET", &full_path, None, None)?;
+        let resp = self
+            .get_signed(Endpoint::Timetable, &endpoint, "trip update")
+            .await?;
+
+        let text = resp.text();
+        // log_debug(&format!("Trip update response for {}: {}", trip_id, text));
 
-        let url = format!("{}{}", BASE_URL, endpoint);
-        let mut req = self.client.get(&url);
+        let response: TripUpdateResponse =
+            serde_json::from_str(&text).context("failed to parse trip update response")?;
+
+        Ok(response)
+    }
+
+    /// Sends a signed GET, re-registering and retrying once if the gateway rejects the credentials.
+    async fn get_signed(&self, kind: Endpoint, endpoint: &str, what: &str) -> Result<HttpResponse> {
+        let (resp, generation) = self.send_guarded(kind, endpoint, what).await?;
+        if resp.status().is_success() {
+            return Ok(resp);
+        }
+
+        let status = resp.status();
+        let text = resp.text();
+        if !is_auth_failure(status) {
+            anyhow::bail!("{} fetch failed: {} - {}", what, status, text);
+        }
+
+        log_debug(&format!(
+            "Credentials rejected on {} with {}: {}",
+            endpoint, status, text
+        ));
+        self.reregister(generation).await?;
+
+        let (resp, _) = self.send_guarded(kind, endpoint, what).await?;
+        if !resp.status().is_success() {
+            anyhow::bail!(
+                "{} fetch failed after re-registration: {} - {}",
+                what,
+                resp.status(),
+                resp.text()
+            );
+        }
+
+        Ok(resp)
+    }
+
+    /// [`UnwireClient::send_signed`] behind the endpoint's circuit breaker. Transport errors,
+    /// 5xx and 429 count as failures; anything else proves the gateway is up.
+    async fn send_guarded(
+        &self,
+        kind: Endpoint,
+        endpoint: &str,
+        what: &str,
+    ) -> Result<(HttpResponse, u64)> {
+        let breaker = self.breakers.get(kind);
+        breaker.allow()?;
+
+        let result = self.send_signed(endpoint, what).await;
+        match &result {
+            Ok((resp, _)) if is_gateway_failure(resp.status()) => breaker.record_failure(),
+            Ok(_) => breaker.record_success(),
+            Err(_) => breaker.record_failure(),
+        }
+        result
+    }
+
+    /// Sends a signed GET, retrying transient failures per the config's [`RetryPolicy`].
+    ///
+    /// [`RetryPolicy`]: crate::retry::RetryPolicy
+    async fn send_signed(&self, endpoint: &str, what: &str) -> Result<(HttpResponse, u64)> {
+        self.config
+            .retry
+            .send(what, || self.send_signed_once(endpoint, what))
+            .await
+    }
+
+    async fn send_signed_once(&self, endpoint: &str, what: &str) -> Result<(HttpResponse, u64)> {
+        let full_path = self.config.signed_path(endpoint);
+        let (headers, generation) = {
+            let authenticator = self.authenticator.read().await;
+            let headers = authenticator.get_auth_headers("GET", &full_path, None, None)?;
+            (headers, authenticator.generation())
+        };
+
+        let mut req = HttpRequest::get(self.config.url(endpoint));
 
         for (k, v) in headers {
             req = req.header(k, v);
@@ -93,45 +224,324 @@ impl UnwireClient {
 
         req = self.add_common_headers(req);
 
-        let resp = req
-            .send()
+        let permit = self.throttle.acquire().await;
+        let resp = match self.transport.send(req).await {
+            Ok(resp) => resp,
+            Err(e) => {
+                permit.record_failure();
+                return Err(e).with_context(|| format!("failed to send {} request", what));
+            }
+        };
+        permit.record(resp.status());
+
+        Ok((resp, generation))
+    }
+
+    /// Registers a new app instance unless another clone already did so since `generation`.
+    async fn reregister(&self, generation: u64) -> Result<()> {
+        let mut authenticator = self.authenticator.write().await;
+        if authenticator.generation() != generation {
+            return Ok(());
+        }
+
+        register_guarded(&mut authenticator, &self.breakers, &self.throttle)
             .await
-            .context("failed to send trip update request")?;
+            .context("failed to re-register app instance")
+    }
 
-        if !resp.status().is_success() {
-            let status = resp.status();
-            let text = resp.text().await.unwrap_or_default();
-            anyhow::bail!("Trip update fetch failed: {} - {}", status, text);
+    fn add_common_headers(&self, mut req: HttpRequest) -> HttpRequest {
+        for (k, v) in &self.config.headers {
+            req = req.header(k, v);
         }
+        req
+    }
+}
 
-        let text = resp.text().await.context("failed to read response text")?;
-        // log_debug(&format!("Trip update response for {}: {}", trip_id, text));
+/// Registers a new app instance behind the app instance circuit breaker and the throttle.
+///
+/// Credentials the authenticator already holds have been rejected, so their cached copy is
+/// discarded first.
+async fn register_guarded(
+    authenticator: &mut Authenticator,
+    breakers: &CircuitBreakers,
+    throttle: &Throttle,
+) -> Result<()> {
+    let breaker = breakers.get(Endpoint::AppInstance);
+    breaker.allow()?;
 
-        let response: TripUpdateResponse =
-            serde_json::from_str(&text).context("failed to parse trip update response")?;
+    if authenticator.is_registered()
+        && let Err(e) = authenticator.discard_cached()
+    {
+        eprintln!("Failed to discard rejected credentials: {:#}", e);
+    }
 
-        Ok(response)
+    let result = {
+        let _permit = throttle.acquire().await;
+        authenticator.register().await
+    };
+    match &result {
+        Ok(()) => breaker.record_success(),
+        Err(_) => breaker.record_failure(),
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::circuit::{CircuitOpen, CircuitState};
+    use crate::credentials::{CredentialStore, FileCredentialStore, StoredCredentials};
+    use crate::model::TripState;
+    use crate::transport::InMemoryTransport;
+    use reqwest::Method;
+    use rsa::RsaPrivateKey;
+    use rsa::pkcs8::{EncodePrivateKey, LineEnding};
+    use std::path::PathBuf;
+    use std::sync::LazyLock;
+    use std::time::Duration;
+
+    const INSTANCE_ID: &str = "test-instance";
+    const APP_INSTANCE_ENDPOINT: &str = "/v1/appinstance";
+
+    static PRIVATE_KEY_PEM: LazyLock<String> = LazyLock::new(|| {
+        let key = RsaPrivateKey::new(&mut rand::rngs::OsRng, 1024).unwrap();
+        key.to_pkcs8_pem(LineEnding::LF).unwrap().to_string()
+    });
+
+    /// An in-memory gateway and a config whose credentials file already holds a registered
+    /// app instance, so clients start without registering.
+    struct Gateway {
+        transport: Arc<InMemoryTransport>,
+        config: UnwireConfig,
+        credentials: PathBuf,
+    }
+
+    impl Gateway {
+        fn new(name: &str) -> Self {
+            let credentials = std::env::temp_dir().join(format!(
+                "unwire-client-test-{}-{}.json",
+                name,
+                std::process::id()
+            ));
+            let mut config = UnwireConfig {
+                base_url: "https://gateway.test".to_string(),
+                credentials_path: Some(credentials.clone()),
+                ..UnwireConfig::default()
+            };
+            config.retry.base_delay_ms = 1;
+
+            FileCredentialStore::new(&credentials)
+                .save(&StoredCredentials {
+                    tenant_id: config.tenant_id.clone(),
+                    private_key_pem: PRIVATE_KEY_PEM.clone(),
+                    instance_id: INSTANCE_ID.to_string(),
+                    decrypted_secret: "secret".to_string(),
+                })
+                .unwrap();
+
+            Self {
+                transport: Arc::new(InMemoryTransport::new()),
+                config,
+                credentials,
+            }
+        }
+
+        fn respond(&self, method: Method, endpoint: &str, response: HttpResponse) {
+            self.transport
+                .respond(method, self.config.signed_path(endpoint), response);
+        }
+
+        async fn client(&self) -> UnwireClient {
+            UnwireClient::with_transport(self.config.clone(), self.transport.clone())
+                .await
+                .unwrap()
+        }
+
+        fn requests_to(&self, endpoint: &str) -> usize {
+            let path = self.config.signed_path(endpoint);
+            self.transport
+                .requests()
+                .iter()
+                .filter(|request| request.path().unwrap() == path)
+                .count()
+        }
+    }
+
+    impl Drop for Gateway {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_file(&self.credentials);
+        }
     }
 
-    fn add_common_headers(&self, req: reqwest::RequestBuilder) -> reqwest::RequestBuilder {
-        req.header(
-            "User-Agent",
-            "Mozilla/5.0 (Windows NT 10.0; Win64; x64; rv:145.0) Gecko/20100101 Firefox/145.0",
+    fn ok(body: &str) -> HttpResponse {
+        HttpResponse::new(StatusCode::OK, body)
+    }
+
+    fn timetable_endpoint(trip_id: &str) -> String {
+        format!(
+            "{}{}{}",
+            TRIPS_ENDPOINT_PREFIX, trip_id, TRIPS_ENDPOINT_SUFFIX
         )
-        .header("Accept", "application/json")
-        .header("Accept-Language", "en-CA,en-US;q=0.7,en;q=0.3")
-        .header("Accept-Encoding", "gzip, deflate, br, zstd")
-        .header("Content-Type", "application/json;charset=UTF-8")
-        .header("Referer", "https://dart.mygopass.org/")
-        .header("Origin", "https://dart.mygopass.org")
-        .header("Connection", "keep-alive")
-        .header("Sec-Fetch-Dest", "empty")
-        .header("Sec-Fetch-Mode", "cors")
-        .header("Sec-Fetch-Site", "cross-site")
-        .header("DNT", "1")
-        .header("Sec-GPC", "1")
-        .header("Pragma", "no-cache")
-        .header("Cache-Control", "no-cache")
-        .header("TE", "trailers")
+    }
+
+    const SNAPSHOT: &str = r#"{"content":[{
+        "id":"DART-42",
+        "coordinate":{"lat":33.2,"lng":-97.1},
+        "trip":{"id":"1234","feedId":"DART"},
+        "route":{"id":"A","feedId":"DART"}
+    }]}"#;
+
+    const TIMETABLE: &str = r#"{"state":"REALTIME","entries":[{
+        "stop":{"id":"DART:S1","name":"Downtown","index":1},
+        "departure":{"state":"PREDICTED","scheduled":"2024-03-02T14:00:00Z","real":"2024-03-02T14:02:00Z"}
+    }]}"#;
+
+    #[tokio::test]
+    async fn uses_cached_credentials_without_registering() {
+        let gateway = Gateway::new("cached");
+        gateway.respond(Method::GET, VEHICLES_ENDPOINT, ok(SNAPSHOT));
+
+        let client = gateway.client().await;
+        client.fetch_vehicles().await.unwrap();
+
+        assert_eq!(gateway.requests_to(APP_INSTANCE_ENDPOINT), 0);
+        let request = &gateway.transport.requests()[0];
+        let hmac = request
+            .headers
+            .iter()
+            .find(|(name, _)| name == "SSG-Instance-HMAC")
+            .map(|(_, value)| value.as_str())
+            .unwrap();
+        assert!(hmac.starts_with("test-instance:"));
+    }
+
+    #[tokio::test]
+    async fn fetches_vehicles_and_trip_updates() {
+        let gateway = Gateway::new("happy");
+        gateway.respond(Method::GET, VEHICLES_ENDPOINT, ok(SNAPSHOT));
+        gateway.respond(Method::GET, &timetable_endpoint("DART:1234"), ok(TIMETABLE));
+        let client = gateway.client().await;
+
+        let snapshot = client.fetch_vehicles().await.unwrap();
+        assert_eq!(snapshot.content.len(), 1);
+        assert_eq!(snapshot.content[0].id, "DART-42");
+
+        let timetable = client.fetch_trip_updates("DART:1234").await.unwrap();
+        assert_eq!(timetable.state, Some(TripState::Realtime));
+        assert_eq!(timetable.entries[0].stop.name.as_deref(), Some("Downtown"));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn retries_a_503_after_retry_after() {
+        let gateway = Gateway::new("retry");
+        gateway.respond(
+            Method::GET,
+            VEHICLES_ENDPOINT,
+            HttpResponse::new(StatusCode::SERVICE_UNAVAILABLE, "busy")
+                .with_header("Retry-After", "2"),
+        );
+        gateway.respond(Method::GET, VEHICLES_ENDPOINT, ok(SNAPSHOT));
+        let client = gateway.client().await;
+
+        let start = tokio::time::Instant::now();
+        client.fetch_vehicles().await.unwrap();
+
+        assert!(start.elapsed() >= Duration::from_secs(2));
+        assert_eq!(gateway.requests_to(VEHICLES_ENDPOINT), 2);
+    }
+
+    #[tokio::test]
+    async fn open_circuit_short_circuits_requests() {
+        let mut gateway = Gateway::new("circuit");
+        gateway.config.retry.max_attempts = 1;
+        gateway.config.circuit_breaker.failure_threshold = 2;
+        gateway.respond(
+            Method::GET,
+            VEHICLES_ENDPOINT,
+            HttpResponse::new(StatusCode::INTERNAL_SERVER_ERROR, "down"),
+        );
+        let client = gateway.client().await;
+
+        for _ in 0..2 {
+            let error = client.fetch_vehicles().await.unwrap_err();
+            assert!(error.downcast_ref::<CircuitOpen>().is_none());
+        }
+        let error = client.fetch_vehicles().await.unwrap_err();
+
+        assert!(error.downcast_ref::<CircuitOpen>().is_some());
+        assert_eq!(gateway.requests_to(VEHICLES_ENDPOINT), 2);
+        let snapshot = client
+            .circuit_statuses()
+            .into_iter()
+            .find(|status| status.endpoint == Endpoint::Snapshot)
+            .unwrap();
+        assert_eq!(snapshot.state, CircuitState::Open);
+        assert_eq!(snapshot.rejected, 1);
+    }
+
+    #[tokio::test]
+    async fn client_error_mentioning_expiry_keeps_credentials() {
+        let gateway = Gateway::new("expired-trip");
+        gateway.respond(
+            Method::GET,
+            &timetable_endpoint("DART:1"),
+            HttpResponse::new(StatusCode::NOT_FOUND, "Trip DART:1 has expired"),
+        );
+        let client = gateway.client().await;
+
+        assert!(client.fetch_trip_updates("DART:1").await.is_err());
+        assert_eq!(gateway.requests_to(APP_INSTANCE_ENDPOINT), 0);
+        assert!(gateway.credentials.exists());
+    }
+
+    #[tokio::test]
+    async fn unauthorized_response_reregisters() {
+        let mut gateway = Gateway::new("unauthorized");
+        gateway.config.retry.max_attempts = 1;
+        gateway.respond(
+            Method::GET,
+            VEHICLES_ENDPOINT,
+            HttpResponse::new(StatusCode::UNAUTHORIZED, ""),
+        );
+        gateway.respond(
+            Method::POST,
+            APP_INSTANCE_ENDPOINT,
+            HttpResponse::new(StatusCode::INTERNAL_SERVER_ERROR, ""),
+        );
+        let client = gateway.client().await;
+
+        assert!(client.fetch_vehicles().await.is_err());
+        assert_eq!(gateway.requests_to(APP_INSTANCE_ENDPOINT), 1);
+        assert!(!gateway.credentials.exists());
+    }
+
+    #[tokio::test]
+    async fn failed_registrations_open_the_app_instance_circuit() {
+        let mut gateway = Gateway::new("register");
+        gateway.config.credentials_path = None;
+        gateway.config.retry.max_attempts = 1;
+        gateway.config.circuit_breaker.failure_threshold = 2;
+        gateway.respond(
+            Method::POST,
+            APP_INSTANCE_ENDPOINT,
+            HttpResponse::new(StatusCode::INTERNAL_SERVER_ERROR, ""),
+        );
+        let breakers = Arc::new(CircuitBreakers::new(&gateway.config.circuit_breaker));
+        let connect = || {
+            UnwireClient::with_breakers(
+                gateway.config.clone(),
+                gateway.transport.clone(),
+                breakers.clone(),
+            )
+        };
+
+        for _ in 0..2 {
+            let error = connect().await.err().unwrap();
+            assert!(error.downcast_ref::<CircuitOpen>().is_none());
+        }
+        let error = connect().await.err().unwrap();
+
+        assert!(error.downcast_ref::<CircuitOpen>().is_some());
+        assert_eq!(gateway.requests_to(APP_INSTANCE_ENDPOINT), 2);
     }
 }
@@ -0,0 +1,212 @@
+use crate::agency::Agency;
+use crate::circuit::CircuitBreakerConfig;
+use crate::credentials::DEFAULT_CREDENTIALS_PATH;
+use crate::retry::RetryPolicy;
+use crate::throttle::ThrottleConfig;
+use crate::timetable_cache::TimetableCacheConfig;
+use crate::transport::TimeoutConfig;
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// Environment variable pointing at a TOML or JSON config file.
+pub const CONFIG_PATH_ENV: &str = "UNWIRE_CONFIG";
+
+/// Connection settings for an Unwire ticketing gateway.
+///
+/// Defaults match the DART GoPass web app on tenant 205.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct UnwireConfig {
+    /// Scheme and host of the gateway, without a trailing slash.
+    pub base_url: String,
+    /// Path prefix the gateway is mounted under; it is part of the signed path.
+    pub base_path: String,
+    pub tenant_id: String,
+    pub developer_key: String,
+    pub developer_secret: String,
+    pub os_type: String,
+    pub hardware_id: String,
+    /// Where registered credentials are cached; `None` disables persistence.
+    pub credentials_path: Option<PathBuf>,
+    /// Browser-style headers sent with every signed request.
+    pub headers: BTreeMap<String, String>,
+    pub timeouts: TimeoutConfig,
+    /// Agencies added to the built-in registry.
+    pub agencies: Vec<Agency>,
+    /// TOML or JSON file with more agencies, see [`AgencyRegistry::from_file`]. Entries in
+    /// `agencies` win over the file's for the same id.
+    ///
+    /// [`AgencyRegistry::from_file`]: crate::agency::AgencyRegistry::from_file
+    pub agencies_path: Option<PathBuf>,
+    pub retry: RetryPolicy,
+    pub throttle: ThrottleConfig,
+    pub circuit_breaker: CircuitBreakerConfig,
+    pub timetable_cache: TimetableCacheConfig,
+}
+
+impl Default for UnwireConfig {
+    fn default() -> Self {
+        let headers = [
+            (
+                "User-Agent",
+                "Mozilla/5.0 (Windows NT 10.0; Win64; x64; rv:145.0) Gecko/20100101 Firefox/145.0",
+            ),
+            ("Accept", "application/json"),
+            ("Accept-Language", "en-CA,en-US;q=0.7,en;q=0.3"),
+            ("Accept-Encoding", "gzip, deflate, br, zstd"),
+            ("Content-Type", "application/json;charset=UTF-8"),
+            ("Referer", "https://dart.mygopass.org/"),
+            ("Origin", "https://dart.mygopass.org"),
+            ("Connection", "keep-alive"),
+            ("Sec-Fetch-Dest", "empty"),
+            ("Sec-Fetch-Mode", "cors"),
+            ("Sec-Fetch-Site", "cross-site"),
+            ("DNT", "1"),
+            ("Sec-GPC", "1"),
+            ("Pragma", "no-cache"),
+            ("Cache-Control", "no-cache"),
+            ("TE", "trailers"),
+        ]
+        .into_iter()
+        .map(|(k, v)| (k.to_string(), v.to_string()))
+        .collect();
+
+        Self {
+            base_url: "https://ssge-ticketing.us.unwire.com".to_string(),
+            base_path: "/api-gateway".to_string(),
+            tenant_id: "205".to_string(),
+            developer_key: "057e903f-48b3-4461-b9fc-39d14ca829ce".to_string(),
+            developer_secret: "35a425ad968fbe5e4ec8364e8e500420".to_string(),
+            os_type: "Android".to_string(),
+            hardware_id: "1234".to_string(),
+            credentials_path: Some(PathBuf::from(DEFAULT_CREDENTIALS_PATH)),
+            headers,
+            timeouts: TimeoutConfig::default(),
+            agencies: Vec::new(),
+            agencies_path: None,
+            retry: RetryPolicy::default(),
+            throttle: ThrottleConfig::default(),
+            circuit_breaker: CircuitBreakerConfig::default(),
+            timetable_cache: TimetableCacheConfig::default(),
+        }
+    }
+}
+
+impl UnwireConfig {
+    /// Loads the file named by `UNWIRE_CONFIG` (if set), then applies `UNWIRE_*` overrides.
+    pub fn load() -> Result<Self> {
+        let config = match env::var_os(CONFIG_PATH_ENV) {
+            Some(path) => Self::from_file(path)?,
+            None => Self::default(),
+        };
+        Ok(config.with_env_overrides())
+    }
+
+    /// Defaults with `UNWIRE_*` environment overrides applied.
+    pub fn from_env() -> Self {
+        Self::default().with_env_overrides()
+    }
+
+    /// Reads a config file; `.json` files are parsed as JSON, anything else as TOML.
+    pub fn from_file(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        let text = fs::read_to_string(path)
+            .with_context(|| format!("failed to read config {}", path.display()))?;
+
+        let is_json = path
+            .extension()
+            .is_some_and(|ext| ext.eq_ignore_ascii_case("json"));
+
+        if is_json {
+            serde_json::from_str(&text)
+                .with_context(|| format!("failed to parse config {}", path.display()))
+        } else {
+            toml::from_str(&text)
+                .with_context(|| format!("failed to parse config {}", path.display()))
+        }
+    }
+
+    pub fn with_env_overrides(mut self) -> Self {
+        let overrides: [(&str, &mut String); 7] = [
+            ("UNWIRE_BASE_URL", &mut self.base_url),
+            ("UNWIRE_BASE_PATH", &mut self.base_path),
+            ("UNWIRE_TENANT_ID", &mut self.tenant_id),
+            ("UNWIRE_DEVELOPER_KEY", &mut self.developer_key),
+            ("UNWIRE_DEVELOPER_SECRET", &mut self.developer_secret),
+            ("UNWIRE_OS_TYPE", &mut self.os_type),
+            ("UNWIRE_HARDWARE_ID", &mut self.hardware_id),
+        ];
+        for (key, field) in overrides {
+            if let Ok(value) = env::var(key) {
+                *field = value;
+            }
+        }
+
+        match env::var("UNWIRE_CREDENTIALS_PATH") {
+            Ok(path) if path.is_empty() => self.credentials_path = None,
+            Ok(path) => self.credentials_path = Some(PathBuf::from(path)),
+            Err(_) => {}
+        }
+        match env::var("UNWIRE_AGENCIES_PATH") {
+            Ok(path) if path.is_empty() => self.agencies_path = None,
+            Ok(path) => self.agencies_path = Some(PathBuf::from(path)),
+            Err(_) => {}
+        }
+
+        self
+    }
+
+    /// Settings for the tenant serving `agency`, with its web-app headers applied.
+    ///
+    /// Credentials for other tenants are cached next to the default file, suffixed by tenant id.
+    pub fn for_agency(&self, agency: &Agency) -> UnwireConfig {
+        let mut config = self.clone();
+
+        if agency.tenant_id != self.tenant_id {
+            config.credentials_path = self
+                .credentials_path
+                .as_deref()
+                .map(|path| tenant_credentials_path(path, &agency.tenant_id));
+            config.tenant_id = agency.tenant_id.clone();
+        }
+        if let Some(referer) = &agency.referer {
+            config
+                .headers
+                .insert("Referer".to_string(), referer.clone());
+        }
+        if let Some(origin) = &agency.origin {
+            config.headers.insert("Origin".to_string(), origin.clone());
+        }
+
+        config
+    }
+
+    /// Path that goes into the request signature, e.g. `/api-gateway/v1/appinstance`.
+    pub fn signed_path(&self, endpoint: &str) -> String {
+        format!("{}{}", self.base_path, endpoint)
+    }
+
+    pub fn url(&self, endpoint: &str) -> String {
+        format!(
+            "{}{}",
+            self.base_url.trim_end_matches('/'),
+            self.signed_path(endpoint)
+        )
+    }
+}
+
+fn tenant_credentials_path(path: &Path, tenant_id: &str) -> PathBuf {
+    let stem = path
+        .file_stem()
+        .map(|stem| stem.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    let file_name = match path.extension() {
+        Some(ext) => format!("{}-{}.{}", stem, tenant_id, ext.to_string_lossy()),
+        None => format!("{}-{}", stem, tenant_id),
+    };
+    path.with_file_name(file_name)
+}
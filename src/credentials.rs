@@ -0,0 +1,109 @@
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+pub const DEFAULT_CREDENTIALS_PATH: &str = "unwire_credentials.json";
+
+/// Everything needed to sign requests as a previously registered app instance.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct StoredCredentials {
+    pub tenant_id: String,
+    pub private_key_pem: String,
+    pub instance_id: String,
+    pub decrypted_secret: String,
+}
+
+pub trait CredentialStore: Send + Sync {
+    /// Returns `Ok(None)` when nothing has been saved yet.
+    fn load(&self) -> Result<Option<StoredCredentials>>;
+    fn save(&self, credentials: &StoredCredentials) -> Result<()>;
+    fn clear(&self) -> Result<()>;
+}
+
+/// Stores credentials as a JSON file on disk.
+#[derive(Debug, Clone)]
+pub struct FileCredentialStore {
+    path: PathBuf,
+}
+
+impl FileCredentialStore {
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        Self { path: path.into() }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl Default for FileCredentialStore {
+    fn default() -> Self {
+        Self::new(DEFAULT_CREDENTIALS_PATH)
+    }
+}
+
+impl CredentialStore for FileCredentialStore {
+    fn load(&self) -> Result<Option<StoredCredentials>> {
+        let text = match fs::read_to_string(&self.path) {
+            Ok(text) => text,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => {
+                return Err(e).with_context(|| {
+                    format!("failed to read credentials from {}", self.path.display())
+                });
+            }
+        };
+
+        let credentials = serde_json::from_str(&text)
+            .with_context(|| format!("failed to parse credentials in {}", self.path.display()))?;
+
+        Ok(Some(credentials))
+    }
+
+    fn save(&self, credentials: &StoredCredentials) -> Result<()> {
+        let json = serde_json::to_string_pretty(credentials)?;
+
+        // Write to a sibling file first so a crash never leaves a truncated store behind.
+        let tmp_path = self.path.with_extension("tmp");
+        write_private(&tmp_path, json.as_bytes())
+            .with_context(|| format!("failed to write credentials to {}", tmp_path.display()))?;
+
+        fs::rename(&tmp_path, &self.path)
+            .with_context(|| format!("failed to move credentials to {}", self.path.display()))?;
+
+        Ok(())
+    }
+
+    fn clear(&self) -> Result<()> {
+        match fs::remove_file(&self.path) {
+            Ok(()) => Ok(()),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(()),
+            Err(e) => Err(e)
+                .with_context(|| format!("failed to remove credentials {}", self.path.display())),
+        }
+    }
+}
+
+/// Writes `contents` to a file only the owner can read, restricted from the moment it is
+/// created rather than after the secret is already on disk.
+fn write_private(path: &Path, contents: &[u8]) -> std::io::Result<()> {
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+
+    let mut file = options.open(path)?;
+    // `mode` only applies to new files; a leftover temp file keeps its old permissions.
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        file.set_permissions(fs::Permissions::from_mode(0o600))?;
+    }
+    file.write_all(contents)
+}
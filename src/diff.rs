@@ -0,0 +1,124 @@
+use crate::gtfs::{FeedEntity, FeedHeader, FeedMessage, Incrementality};
+use std::collections::{HashMap, HashSet};
+
+/// A `DIFFERENTIAL` message that turns `previous` into `current`.
+///
+/// Entities that are new or changed are included in full; entities missing from `current`
+/// are sent as `is_deleted` tombstones carrying only their id.
+pub fn diff_feeds(previous: &FeedMessage, current: &FeedMessage) -> FeedMessage {
+    let before: HashMap<&str, &FeedEntity> = previous
+        .entity
+        .iter()
+        .map(|entity| (entity.id.as_str(), entity))
+        .collect();
+    let current_ids: HashSet<&str> = current.entity.iter().map(|e| e.id.as_str()).collect();
+
+    let changed = current
+        .entity
+        .iter()
+        .filter(|entity| before.get(entity.id.as_str()) != Some(entity))
+        .cloned();
+    let deleted = previous
+        .entity
+        .iter()
+        .filter(|entity| !current_ids.contains(entity.id.as_str()))
+        .map(|entity| tombstone(&entity.id));
+
+    FeedMessage {
+        header: differential_header(&current.header),
+        entity: changed.chain(deleted).collect(),
+    }
+}
+
+/// `message` relabelled as a differential update, for the first message of a stream when
+/// there is nothing to diff against.
+pub fn as_differential(message: &FeedMessage) -> FeedMessage {
+    FeedMessage {
+        header: differential_header(&message.header),
+        entity: message.entity.clone(),
+    }
+}
+
+fn differential_header(header: &FeedHeader) -> FeedHeader {
+    FeedHeader {
+        incrementality: Some(Incrementality::Differential as i32),
+        ..header.clone()
+    }
+}
+
+fn tombstone(id: &str) -> FeedEntity {
+    FeedEntity {
+        id: id.to_string(),
+        is_deleted: Some(true),
+        trip_update: None,
+        vehicle: None,
+        alert: None,
+        shape: None,
+        stop: None,
+        trip_modifications: None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::gtfs::{TripDescriptor, VehiclePosition};
+
+    fn vehicle(id: &str, trip_id: &str) -> FeedEntity {
+        FeedEntity {
+            id: id.to_string(),
+            vehicle: Some(VehiclePosition {
+                trip: Some(TripDescriptor {
+                    trip_id: Some(trip_id.to_string()),
+                    ..Default::default()
+                }),
+                ..Default::default()
+            }),
+            ..tombstone(id)
+        }
+    }
+
+    fn message(entity: Vec<FeedEntity>) -> FeedMessage {
+        FeedMessage {
+            header: FeedHeader {
+                gtfs_realtime_version: "2.0".to_string(),
+                timestamp: Some(100),
+                ..Default::default()
+            },
+            entity,
+        }
+    }
+
+    #[test]
+    fn sends_changed_and_new_entities_and_tombstones() {
+        let previous = message(vec![
+            vehicle("a", "1"),
+            vehicle("b", "2"),
+            vehicle("c", "3"),
+        ]);
+        let current = message(vec![
+            vehicle("b", "20"),
+            vehicle("c", "3"),
+            vehicle("d", "4"),
+        ]);
+
+        let diff = diff_feeds(&previous, &current);
+
+        let ids: Vec<_> = diff.entity.iter().map(|e| e.id.as_str()).collect();
+        assert_eq!(ids, ["b", "d", "a"]);
+        assert_eq!(diff.entity[0], current.entity[0]);
+        assert_eq!(diff.entity[2].is_deleted, Some(true));
+        assert!(diff.entity[2].vehicle.is_none());
+        assert_eq!(
+            diff.header.incrementality,
+            Some(Incrementality::Differential as i32)
+        );
+        assert_eq!(diff.header.timestamp, Some(100));
+    }
+
+    #[test]
+    fn identical_feeds_diff_to_nothing() {
+        let feed = message(vec![vehicle("a", "1")]);
+        assert!(diff_feeds(&feed, &feed).entity.is_empty());
+    }
+}
@@ -0,0 +1,512 @@
+use crate::agency::{self, AgencyRegistry, FeedId};
+use crate::circuit::{CircuitBreakers, CircuitOpen, CircuitStatus};
+use crate::client::UnwireClient;
+use crate::config::UnwireConfig;
+use crate::gtfs::{
+    ConversionOptions, FeedEntity, FeedMessage, combine_feeds, convert_trip_update_for_vehicle,
+    fill_stop_status, fill_trip_starts, full_dataset_header,
+};
+use crate::model::{TripUpdateResponse, VehicleContent};
+use crate::timetable_cache::TimetableCache;
+use crate::{convert_to_gtfs, normalize_trip_id, strip_prefix, vehicle_matches_feed};
+use anyhow::{Context, Result};
+use futures::{StreamExt, stream};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::sync::Arc;
+use std::time::Instant;
+use tokio::sync::{Mutex, OnceCell};
+
+static SHARED: OnceCell<FeedFetcher> = OnceCell::const_new();
+
+/// Another tenant's client, created on first use.
+///
+/// The breakers outlive failed registrations, so a tenant that keeps refusing is not asked
+/// again on every poll while its app instance circuit is open.
+struct Tenant {
+    client: OnceCell<UnwireClient>,
+    breakers: Arc<CircuitBreakers>,
+}
+
+/// Long-lived session that owns one registered [`UnwireClient`] per tenant and reuses it for
+/// every fetch.
+///
+/// Agencies on the same tenant share a client, so they also share its `Referer`/`Origin`.
+#[derive(Clone)]
+pub struct FeedFetcher {
+    config: Arc<UnwireConfig>,
+    client: UnwireClient,
+    tenants: Arc<Mutex<HashMap<String, Arc<Tenant>>>>,
+    timetables: Arc<Mutex<TimetableCache>>,
+}
+
+impl FeedFetcher {
+    pub async fn new() -> Result<Self> {
+        Self::from_client(UnwireClient::new().await?)
+    }
+
+    pub async fn with_config(config: UnwireConfig) -> Result<Self> {
+        Self::from_client(UnwireClient::with_config(config).await?)
+    }
+
+    /// Uses `client` for its tenant and registers the agencies listed in its config and in
+    /// its `agencies_path` file.
+    ///
+    /// Agencies live in a process-wide registry, so this fails if another fetcher already
+    /// registered one of them with different settings.
+    pub fn from_client(client: UnwireClient) -> Result<Self> {
+        let config = Arc::new(client.config().clone());
+        let mut agencies = match &config.agencies_path {
+            Some(path) => AgencyRegistry::from_file(path)?.iter().cloned().collect(),
+            None => Vec::new(),
+        };
+        agencies.extend(config.agencies.iter().cloned());
+        agency::register(agencies).context("failed to register configured agencies")?;
+
+        Ok(Self {
+            timetables: Arc::new(Mutex::new(TimetableCache::new(&config.timetable_cache))),
+            config,
+            client,
+            tenants: Arc::new(Mutex::new(HashMap::new())),
+        })
+    }
+
+    /// Process-wide fetcher used by the free functions in the crate root.
+    pub async fn shared() -> Result<&'static FeedFetcher> {
+        SHARED.get_or_try_init(FeedFetcher::new).await
+    }
+
+    /// Circuit breaker state of every tenant used so far, keyed by tenant id.
+    pub async fn circuit_statuses(&self) -> BTreeMap<String, Vec<CircuitStatus>> {
+        let mut statuses: BTreeMap<_, _> = self
+            .tenants
+            .lock()
+            .await
+            .iter()
+            .map(|(tenant_id, tenant)| (tenant_id.clone(), tenant.breakers.statuses()))
+            .collect();
+        statuses.insert(
+            self.config.tenant_id.clone(),
+            self.client.circuit_statuses(),
+        );
+        statuses
+    }
+
+    /// Client for the tenant named in the fetcher's config.
+    pub fn client(&self) -> &UnwireClient {
+        &self.client
+    }
+
+    /// Client for the tenant serving `feed`, registering with that tenant on first use.
+    pub async fn client_for(&self, feed: FeedId) -> Result<UnwireClient> {
+        let agency = agency::registry()
+            .get(feed)
+            .cloned()
+            .with_context(|| format!("unknown feed {}", feed))?;
+
+        if agency.tenant_id == self.config.tenant_id {
+            return Ok(self.client.clone());
+        }
+
+        // Registering can take a while; only hold the map lock to find the tenant's slot.
+        let tenant = self
+            .tenants
+            .lock()
+            .await
+            .entry(agency.tenant_id.clone())
+            .or_insert_with(|| {
+                Arc::new(Tenant {
+                    client: OnceCell::new(),
+                    breakers: Arc::new(CircuitBreakers::new(&self.config.circuit_breaker)),
+                })
+            })
+            .clone();
+
+        let client = tenant
+            .client
+            .get_or_try_init(|| {
+                UnwireClient::with_breakers(
+                    self.config.for_agency(&agency),
+                    self.client.transport().clone(),
+                    tenant.breakers.clone(),
+                )
+            })
+            .await
+            .with_context(|| format!("failed to create client for tenant {}", agency.tenant_id))?;
+        Ok(client.clone())
+    }
+
+    pub async fn fetch_dart_vehicles(&self) -> Result<FeedMessage> {
+        self.fetch_feed_vehicles(FeedId::DART).await
+    }
+
+    pub async fn fetch_feed_vehicles(&self, feed: FeedId) -> Result<FeedMessage> {
+        let mut by_feed = self.fetch_vehicles_by_feed(&[feed]).await?;
+        Ok(by_feed.remove(&feed).unwrap_or_else(empty_feed))
+    }
+
+    /// Vehicle positions for every feed in `feeds`, downloading each tenant's snapshot once.
+    pub async fn fetch_vehicles_by_feed(
+        &self,
+        feeds: &[FeedId],
+    ) -> Result<HashMap<FeedId, FeedMessage>> {
+        let snapshots = self.fetch_vehicle_snapshots(feeds).await?;
+        Ok(vehicle_positions_by_feed(&snapshots))
+    }
+
+    /// Downloads the vehicle snapshot once per tenant and splits it by feed.
+    ///
+    /// Every requested feed gets an entry, even if none of its vehicles are active.
+    pub async fn fetch_vehicle_snapshots(
+        &self,
+        feeds: &[FeedId],
+    ) -> Result<HashMap<FeedId, Vec<VehicleContent>>> {
+        let mut tenants: HashMap<String, (UnwireClient, Vec<FeedId>)> = HashMap::new();
+        for &feed in feeds {
+            let client = self.client_for(feed).await?;
+            tenants
+                .entry(client.config().tenant_id.clone())
+                .or_insert_with(|| (client, Vec::new()))
+                .1
+                .push(feed);
+        }
+
+        let mut by_feed: HashMap<FeedId, Vec<VehicleContent>> =
+            feeds.iter().map(|&feed| (feed, Vec::new())).collect();
+
+        for (client, tenant_feeds) in tenants.into_values() {
+            let snapshot = client.fetch_vehicles().await?;
+            for vehicle in snapshot.content {
+                if let Some(&feed) = tenant_feeds
+                    .iter()
+                    .find(|&&feed| vehicle_matches_feed(&vehicle, feed))
+                {
+                    by_feed.entry(feed).or_default().push(vehicle);
+                }
+            }
+        }
+
+        Ok(by_feed)
+    }
+
+    pub async fn fetch_feed_trip_update(&self, feed: FeedId, trip_id: &str) -> Result<FeedMessage> {
+        let normalized_trip_id = normalize_trip_id(feed, trip_id);
+        let client = self.client_for(feed).await?;
+        let update_response = client.fetch_trip_updates(&normalized_trip_id).await?;
+        let trip_update = convert_trip_update_for_vehicle(
+            normalized_trip_id.clone(),
+            update_response,
+            None,
+            &ConversionOptions::for_feed(feed),
+        );
+
+        let entity = FeedEntity {
+            id: strip_prefix(&normalized_trip_id),
+            is_deleted: Some(false),
+            trip_update: Some(trip_update),
+            vehicle: None,
+            alert: None,
+            shape: None,
+            stop: None,
+            trip_modifications: None,
+        };
+
+        Ok(FeedMessage {
+            header: full_dataset_header(),
+            entity: vec![entity],
+        })
+    }
+
+    pub async fn fetch_dart_trip_updates(&self, trip_id: &str) -> Result<FeedMessage> {
+        self.fetch_feed_trip_update(FeedId::DART, trip_id).await
+    }
+
+    pub async fn fetch_all_dart_trip_updates(&self) -> Result<FeedMessage> {
+        self.fetch_all_feed_trip_updates(FeedId::DART).await
+    }
+
+    pub async fn fetch_all_feed_trip_updates(&self, feed: FeedId) -> Result<FeedMessage> {
+        let mut by_feed = self.fetch_trip_updates_by_feed(&[feed]).await?;
+        Ok(by_feed.remove(&feed).unwrap_or_else(empty_feed))
+    }
+
+    /// Trip updates for every active trip in `feeds`, downloading each tenant's snapshot once.
+    pub async fn fetch_trip_updates_by_feed(
+        &self,
+        feeds: &[FeedId],
+    ) -> Result<HashMap<FeedId, FeedMessage>> {
+        let snapshots = self.fetch_vehicle_snapshots(feeds).await?;
+        self.fetch_trip_updates_for(&snapshots).await
+    }
+
+    /// Vehicle positions with each vehicle's trip update attached, for every feed in `feeds`.
+    pub async fn fetch_combined_by_feed(
+        &self,
+        feeds: &[FeedId],
+    ) -> Result<HashMap<FeedId, FeedMessage>> {
+        let snapshots = self.fetch_vehicle_snapshots(feeds).await?;
+        let timetables = self.fetch_timetables_for(&snapshots).await?;
+        let trip_updates = trip_updates_by_feed(&timetables);
+        let mut vehicle_positions = vehicle_positions_by_feed(&snapshots);
+        fill_stop_status_by_feed(&mut vehicle_positions, &timetables);
+        fill_trip_starts_by_feed(&mut vehicle_positions, &trip_updates);
+        Ok(combined_by_feed(&vehicle_positions, &trip_updates))
+    }
+
+    /// Fetches the timetable of every trip referenced by `snapshots` in one shared fan-out.
+    ///
+    /// Each update is enriched with the vehicle running the trip. Trips that fail to fetch
+    /// are logged and left out of their feed.
+    pub async fn fetch_trip_updates_for(
+        &self,
+        snapshots: &HashMap<FeedId, Vec<VehicleContent>>,
+    ) -> Result<HashMap<FeedId, FeedMessage>> {
+        let timetables = self.fetch_timetables_for(snapshots).await?;
+        Ok(trip_updates_by_feed(&timetables))
+    }
+
+    /// Raw timetables behind [`FeedFetcher::fetch_trip_updates_for`], for callers that also
+    /// need the stop coordinates.
+    ///
+    /// Only trips the [`TimetableCache`] has no fresh timetable for are downloaded; the cache
+    /// forgets trips of these feeds whose vehicles are gone. A trip whose download fails falls
+    /// back to its last cached timetable, if any.
+    ///
+    /// Every feed in `snapshots` gets an entry, even if none of its trips could be fetched.
+    pub async fn fetch_timetables_for(
+        &self,
+        snapshots: &HashMap<FeedId, Vec<VehicleContent>>,
+    ) -> Result<HashMap<FeedId, Vec<TripTimetable>>> {
+        let trips = trips_by_feed(snapshots);
+        let now = Instant::now();
+
+        let mut timetables: HashMap<FeedId, Vec<TripTimetable>> =
+            snapshots.keys().map(|&feed| (feed, Vec::new())).collect();
+        // Resolving a client may register with a new tenant, so do it before locking the cache.
+        let mut clients = HashMap::new();
+        for &feed in trips.keys() {
+            clients.insert(feed, self.client_for(feed).await?);
+        }
+
+        let mut jobs = Vec::new();
+        {
+            let mut cache = self.timetables.lock().await;
+            let feeds = trips.keys().copied().collect();
+            let active = trips
+                .values()
+                .flat_map(|vehicles| vehicles.keys().map(String::as_str))
+                .collect();
+            cache.evict_inactive(&feeds, &active);
+
+            for (&feed, vehicles) in &trips {
+                let client = &clients[&feed];
+                for (trip_id, &vehicle) in vehicles {
+                    let timetable = TripTimetable {
+                        trip_id: trip_id.clone(),
+                        vehicle: vehicle.clone(),
+                        response: match cache.fresh(trip_id, vehicle, now) {
+                            Some(response) => response.clone(),
+                            None => {
+                                jobs.push((feed, client.clone(), trip_id.clone(), vehicle.clone()));
+                                continue;
+                            }
+                        },
+                    };
+                    timetables.entry(feed).or_default().push(timetable);
+                }
+            }
+        }
+
+        let cached: usize = timetables.values().map(Vec::len).sum();
+        println!(
+            "Fetching trip updates for {} trips ({} cached)...",
+            jobs.len(),
+            cached
+        );
+
+        // Each client's throttle adapts the real concurrency below this ceiling.
+        let concurrency = self.config.throttle.max_concurrency.max(1);
+
+        let mut stream = stream::iter(jobs.into_iter().map(
+            |(feed, client, trip_id, vehicle)| async move {
+                let result = client.fetch_trip_updates(&trip_id).await;
+                (feed, trip_id, vehicle, result)
+            },
+        ))
+        .buffer_unordered(concurrency);
+
+        let mut short_circuited = None;
+        while let Some((feed, trip_id, vehicle, result)) = stream.next().await {
+            let mut cache = self.timetables.lock().await;
+            let response = match result {
+                Ok(response) => {
+                    cache.insert(feed, trip_id.clone(), &vehicle, response.clone(), now);
+                    response
+                }
+                Err(e) if e.downcast_ref::<CircuitOpen>().is_some() => {
+                    short_circuited = Some(e);
+                    continue;
+                }
+                Err(e) => {
+                    eprintln!("Failed to fetch update for trip {}: {}", trip_id, e);
+                    match cache.last(&trip_id) {
+                        Some(response) => response.clone(),
+                        None => continue,
+                    }
+                }
+            };
+            timetables.entry(feed).or_default().push(TripTimetable {
+                trip_id,
+                vehicle,
+                response,
+            });
+        }
+
+        // A partial set would replace the last good trip updates with a gutted one.
+        if let Some(e) = short_circuited {
+            return Err(e.context("timetable requests short-circuited"));
+        }
+        Ok(timetables)
+    }
+}
+
+/// One trip's timetable as returned by Unwire, with the vehicle it was fetched for.
+#[derive(Debug, Clone)]
+pub struct TripTimetable {
+    /// Full trip id, e.g. `DART:1234`.
+    pub trip_id: String,
+    pub vehicle: VehicleContent,
+    pub response: TripUpdateResponse,
+}
+
+/// Converts each feed's timetables into a trip updates message.
+pub fn trip_updates_by_feed(
+    timetables: &HashMap<FeedId, Vec<TripTimetable>>,
+) -> HashMap<FeedId, FeedMessage> {
+    timetables
+        .iter()
+        .map(|(&feed, trips)| {
+            let options = ConversionOptions::for_feed(feed);
+            let entity = trips
+                .iter()
+                .map(|timetable| {
+                    let trip_update = convert_trip_update_for_vehicle(
+                        timetable.trip_id.clone(),
+                        timetable.response.clone(),
+                        Some(&timetable.vehicle),
+                        &options,
+                    );
+                    FeedEntity {
+                        id: strip_prefix(&timetable.trip_id),
+                        is_deleted: Some(false),
+                        trip_update: Some(trip_update),
+                        vehicle: None,
+                        alert: None,
+                        shape: None,
+                        stop: None,
+                        trip_modifications: None,
+                    }
+                })
+                .collect();
+            let message = FeedMessage {
+                header: full_dataset_header(),
+                entity,
+            };
+            (feed, message)
+        })
+        .collect()
+}
+
+/// Applies [`fill_stop_status`] to every feed present in both maps.
+pub fn fill_stop_status_by_feed(
+    vehicle_positions: &mut HashMap<FeedId, FeedMessage>,
+    timetables: &HashMap<FeedId, Vec<TripTimetable>>,
+) {
+    for (feed, vehicles) in vehicle_positions.iter_mut() {
+        let Some(trips) = timetables.get(feed) else {
+            continue;
+        };
+        let entries = trips
+            .iter()
+            .map(|t| (strip_prefix(&t.trip_id), t.response.entries.as_slice()))
+            .collect();
+        fill_stop_status(vehicles, &entries);
+    }
+}
+
+/// Converts each feed's vehicles into a vehicle positions message.
+pub fn vehicle_positions_by_feed(
+    snapshots: &HashMap<FeedId, Vec<VehicleContent>>,
+) -> HashMap<FeedId, FeedMessage> {
+    snapshots
+        .iter()
+        .map(|(&feed, vehicles)| (feed, convert_to_gtfs(vehicles.clone())))
+        .collect()
+}
+
+/// Full trip ids (`DART:1234`) of the trips the vehicles in each feed are running.
+pub fn trip_ids_by_feed(
+    snapshots: &HashMap<FeedId, Vec<VehicleContent>>,
+) -> HashMap<FeedId, HashSet<String>> {
+    trips_by_feed(snapshots)
+        .into_iter()
+        .map(|(feed, trips)| (feed, trips.into_keys().collect()))
+        .collect()
+}
+
+/// Like [`trip_ids_by_feed`], keeping the first vehicle seen running each trip.
+pub fn trips_by_feed(
+    snapshots: &HashMap<FeedId, Vec<VehicleContent>>,
+) -> HashMap<FeedId, HashMap<String, &VehicleContent>> {
+    snapshots
+        .iter()
+        .map(|(&feed, vehicles)| {
+            let mut trips = HashMap::new();
+            for vehicle in vehicles {
+                let Some(trip) = &vehicle.trip else {
+                    continue;
+                };
+                let full_trip_id = format!("{}:{}", trip.feed_id, trip.id);
+                if full_trip_id.starts_with(feed.as_str()) {
+                    trips.entry(full_trip_id).or_insert(vehicle);
+                }
+            }
+            (feed, trips)
+        })
+        .collect()
+}
+
+/// Applies [`fill_trip_starts`] to every feed present in both maps.
+pub fn fill_trip_starts_by_feed(
+    vehicle_positions: &mut HashMap<FeedId, FeedMessage>,
+    trip_updates: &HashMap<FeedId, FeedMessage>,
+) {
+    for (feed, vehicles) in vehicle_positions.iter_mut() {
+        if let Some(trips) = trip_updates.get(feed) {
+            fill_trip_starts(vehicles, trips);
+        }
+    }
+}
+
+/// Vehicle positions and trip updates merged per vehicle, see [`combine_feeds`].
+pub fn combined_by_feed(
+    vehicle_positions: &HashMap<FeedId, FeedMessage>,
+    trip_updates: &HashMap<FeedId, FeedMessage>,
+) -> HashMap<FeedId, FeedMessage> {
+    vehicle_positions
+        .iter()
+        .map(|(&feed, vehicles)| {
+            let combined = match trip_updates.get(&feed) {
+                Some(trips) => combine_feeds(vehicles, trips),
+                None => vehicles.clone(),
+            };
+            (feed, combined)
+        })
+        .collect()
+}
+
+fn empty_feed() -> FeedMessage {
+    FeedMessage {
+        header: full_dataset_header(),
+        entity: Vec::new(),
+    }
+}
@@ -0,0 +1,136 @@
+use crate::agency::FeedId;
+use crate::fetcher::TripTimetable;
+use crate::gtfs::{FeedMessage, VehicleStopStatus};
+use crate::model::TimeInfo;
+use crate::strip_prefix;
+use serde_json::{Value, json};
+use std::collections::{BTreeSet, HashMap};
+
+/// One point per vehicle in a vehicle positions message.
+///
+/// Properties are `vehicle_id`, `route_id`, `trip_id`, `headsign`, `bearing`, `speed`,
+/// `stop_id`, `current_status` and `timestamp`, each `null` when unknown, and `stale`, true
+/// for vehicles whose id (or entity id) is in `stale`.
+pub fn vehicles_to_geojson(vehicle_positions: &FeedMessage, stale: &BTreeSet<String>) -> Value {
+    let features: Vec<Value> = vehicle_positions
+        .entity
+        .iter()
+        .filter_map(|entity| {
+            let vehicle = entity.vehicle.as_ref()?;
+            let position = vehicle.position.as_ref()?;
+            let descriptor = vehicle.vehicle.as_ref();
+            let trip = vehicle.trip.as_ref();
+            let status = vehicle
+                .current_status
+                .and_then(|s| VehicleStopStatus::try_from(s).ok())
+                .map(|s| s.as_str_name());
+            let vehicle_id = descriptor.and_then(|d| d.id.as_deref());
+
+            Some(json!({
+                "type": "Feature",
+                "id": entity.id,
+                "geometry": {
+                    "type": "Point",
+                    "coordinates": [position.longitude, position.latitude],
+                },
+                "properties": {
+                    "vehicle_id": vehicle_id,
+                    "route_id": trip.and_then(|t| t.route_id.as_deref()),
+                    "trip_id": trip.and_then(|t| t.trip_id.as_deref()),
+                    "headsign": descriptor.and_then(|d| d.label.as_deref()),
+                    "bearing": position.bearing,
+                    "speed": position.speed,
+                    "stop_id": vehicle.stop_id,
+                    "current_status": status,
+                    "timestamp": vehicle.timestamp,
+                    "stale": stale.contains(vehicle_id.unwrap_or(&entity.id)),
+                },
+            }))
+        })
+        .collect();
+
+    feature_collection(features)
+}
+
+/// One line per trip through the coordinates of its stops, in stop order.
+///
+/// The `stops` property lists each stop with its scheduled and predicted times; stops
+/// without a coordinate are listed but left out of the line.
+pub fn trips_to_geojson(timetables: &[TripTimetable]) -> Value {
+    let features = timetables.iter().filter_map(trip_feature).collect();
+    feature_collection(features)
+}
+
+/// [`trips_to_geojson`] for every feed.
+pub fn trips_to_geojson_by_feed(
+    timetables: &HashMap<FeedId, Vec<TripTimetable>>,
+) -> HashMap<FeedId, Value> {
+    timetables
+        .iter()
+        .map(|(&feed, trips)| (feed, trips_to_geojson(trips)))
+        .collect()
+}
+
+fn trip_feature(timetable: &TripTimetable) -> Option<Value> {
+    let mut entries: Vec<_> = timetable.response.entries.iter().collect();
+    entries.sort_by_key(|entry| entry.stop.index);
+
+    let coordinates: Vec<Value> = entries
+        .iter()
+        .filter_map(|entry| entry.stop.coordinate.as_ref())
+        .map(|c| json!([c.lng, c.lat]))
+        .collect();
+    // A LineString needs at least two positions.
+    if coordinates.len() < 2 {
+        return None;
+    }
+
+    let stops: Vec<Value> = entries
+        .iter()
+        .map(|entry| {
+            let (scheduled_arrival, predicted_arrival) = times(&entry.arrival);
+            let (scheduled_departure, predicted_departure) = times(&entry.departure);
+            json!({
+                "stop_id": strip_prefix(&entry.stop.id),
+                "name": entry.stop.name,
+                "sequence": entry.stop.index,
+                "scheduled_arrival": scheduled_arrival,
+                "predicted_arrival": predicted_arrival,
+                "scheduled_departure": scheduled_departure,
+                "predicted_departure": predicted_departure,
+            })
+        })
+        .collect();
+
+    let vehicle = &timetable.vehicle;
+    Some(json!({
+        "type": "Feature",
+        "id": strip_prefix(&timetable.trip_id),
+        "geometry": {
+            "type": "LineString",
+            "coordinates": coordinates,
+        },
+        "properties": {
+            "trip_id": strip_prefix(&timetable.trip_id),
+            "route_id": vehicle.route.as_ref().map(|r| strip_prefix(&r.id)),
+            "vehicle_id": strip_prefix(&vehicle.id),
+            "headsign": vehicle.head_sign,
+            "state": timetable.response.state.clone().map(String::from),
+            "stops": stops,
+        },
+    }))
+}
+
+fn times(info: &Option<TimeInfo>) -> (Option<&str>, Option<&str>) {
+    match info {
+        Some(info) => (info.scheduled.as_deref(), info.real.as_deref()),
+        None => (None, None),
+    }
+}
+
+fn feature_collection(features: Vec<Value>) -> Value {
+    json!({
+        "type": "FeatureCollection",
+        "features": features,
+    })
+}
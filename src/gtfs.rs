@@ -1,161 +1,690 @@
-use crate::model::{TripUpdateResponse, VehicleContent};
+use crate::agency::{self, FeedId};
+use crate::model::{
+    Coordinate, TimeInfo, TimeState, TripState, TripUpdateEntry, TripUpdateResponse, VehicleContent,
+};
 use crate::strip_prefix;
-use chrono::DateTime;
+use chrono::{DateTime, NaiveDateTime, Timelike};
+use chrono_tz::Tz;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
 use std::time::{SystemTime, UNIX_EPOCH};
 
 pub use gtfs_realtime::{
+    FeedEntity, FeedHeader, FeedMessage, Position, TripDescriptor, TripUpdate, VehicleDescriptor,
+    VehiclePosition,
     feed_header::Incrementality,
-    trip_update::{StopTimeEvent, StopTimeUpdate},
+    trip_descriptor::ScheduleRelationship as TripScheduleRelationship,
+    trip_update::{StopTimeEvent, StopTimeUpdate, stop_time_update::ScheduleRelationship},
     vehicle_position::VehicleStopStatus,
-    FeedEntity,
-    FeedHeader,
-    FeedMessage,
-    Position,
-    TripDescriptor,
-    TripUpdate,
-    VehicleDescriptor,
-    VehiclePosition,
 };
 
 pub fn convert_to_gtfs(vehicles: Vec<VehicleContent>) -> FeedMessage {
+    let entities = vehicles
+        .iter()
+        .map(|v| FeedEntity {
+            id: strip_prefix(&v.id),
+            is_deleted: Some(false),
+            trip_update: None,
+            vehicle: Some(convert_vehicle(v)),
+            alert: None,
+            shape: None,
+            stop: None,
+            trip_modifications: None,
+        })
+        .collect();
+
+    FeedMessage {
+        header: full_dataset_header(),
+        entity: entities,
+    }
+}
+
+fn convert_vehicle(v: &VehicleContent) -> VehiclePosition {
+    let position = Position {
+        latitude: v.coordinate.lat as f32,
+        longitude: v.coordinate.lng as f32,
+        bearing: v.orientation.map(|o| o as f32),
+        odometer: None,
+        speed: None,
+    };
+
+    VehiclePosition {
+        trip: vehicle_trip_descriptor(v),
+        vehicle: Some(vehicle_descriptor(v)),
+        position: Some(position),
+        current_stop_sequence: None,
+        stop_id: v.stop.as_ref().map(|s| strip_prefix(&s.id)),
+        current_status: None,
+        timestamp: v.timestamp.as_ref().and_then(|t| t.unix_seconds()),
+        congestion_level: None,
+        occupancy_status: None,
+        occupancy_percentage: None,
+        multi_carriage_details: Vec::new(),
+    }
+}
+
+fn vehicle_descriptor(v: &VehicleContent) -> VehicleDescriptor {
+    VehicleDescriptor {
+        id: Some(strip_prefix(&v.id)),
+        label: v.head_sign.clone(),
+        license_plate: None,
+        wheelchair_accessible: None,
+    }
+}
+
+fn vehicle_trip_descriptor(v: &VehicleContent) -> Option<TripDescriptor> {
+    let trip = v.trip.as_ref()?;
+    Some(TripDescriptor {
+        trip_id: Some(strip_prefix(&trip.id)),
+        route_id: v.route.as_ref().map(|r| strip_prefix(&r.id)),
+        direction_id: v.direction_id.map(|d| d as u32),
+        start_time: None,
+        start_date: None,
+        schedule_relationship: None,
+        modified_trip: None,
+    })
+}
+
+/// Within this distance of a stop a vehicle counts as `STOPPED_AT` it.
+const STOPPED_AT_METERS: f64 = 30.0;
+/// Within this distance of its next stop a vehicle counts as `INCOMING_AT` it.
+const INCOMING_AT_METERS: f64 = 200.0;
+
+/// Where a vehicle is relative to the stops of the trip it is running.
+#[derive(Debug, Clone, PartialEq)]
+pub struct StopStatus {
+    pub status: VehicleStopStatus,
+    pub stop_id: String,
+    pub stop_sequence: u32,
+}
+
+/// Derives the vehicle's current stop and status from its position and the stop
+/// coordinates in the trip's timetable.
+///
+/// The current stop is the nearest one, unless the vehicle is already closer to the following
+/// stop than that stop is, in which case it has left and is heading to the next.
+pub fn stop_status(position: &Position, entries: &[TripUpdateEntry]) -> Option<StopStatus> {
+    let vehicle = Coordinate {
+        lat: position.latitude as f64,
+        lng: position.longitude as f64,
+    };
+    let mut stops: Vec<_> = entries
+        .iter()
+        .filter_map(|entry| Some((&entry.stop, entry.stop.coordinate.as_ref()?)))
+        .collect();
+    stops.sort_by_key(|(stop, _)| stop.index);
+
+    let distances: Vec<f64> = stops
+        .iter()
+        .map(|(_, coordinate)| distance_meters(&vehicle, coordinate))
+        .collect();
+    let nearest = (0..stops.len()).min_by(|&a, &b| distances[a].total_cmp(&distances[b]))?;
+
+    let target = match stops.get(nearest + 1) {
+        Some((_, next)) if distances[nearest] > STOPPED_AT_METERS => {
+            let gap = distance_meters(stops[nearest].1, next);
+            if distances[nearest + 1] < gap {
+                nearest + 1
+            } else {
+                nearest
+            }
+        }
+        _ => nearest,
+    };
+
+    let status = if distances[target] <= STOPPED_AT_METERS {
+        VehicleStopStatus::StoppedAt
+    } else if distances[target] <= INCOMING_AT_METERS {
+        VehicleStopStatus::IncomingAt
+    } else {
+        VehicleStopStatus::InTransitTo
+    };
+
+    let (stop, _) = stops[target];
+    Some(StopStatus {
+        status,
+        stop_id: strip_prefix(&stop.id),
+        stop_sequence: stop.index,
+    })
+}
+
+/// Great-circle distance between two coordinates.
+pub(crate) fn distance_meters(a: &Coordinate, b: &Coordinate) -> f64 {
+    const EARTH_RADIUS_METERS: f64 = 6_371_000.0;
+
+    let (lat_a, lat_b) = (a.lat.to_radians(), b.lat.to_radians());
+    let d_lat = lat_b - lat_a;
+    let d_lng = (b.lng - a.lng).to_radians();
+    let h = (d_lat / 2.0).sin().powi(2) + lat_a.cos() * lat_b.cos() * (d_lng / 2.0).sin().powi(2);
+    2.0 * EARTH_RADIUS_METERS * h.sqrt().asin()
+}
+
+/// Sets `current_status`, `current_stop_sequence` and `stop_id` on every vehicle whose trip
+/// has a timetable in `timetables`, keyed by stripped trip id.
+pub fn fill_stop_status(
+    vehicle_positions: &mut FeedMessage,
+    timetables: &HashMap<String, &[TripUpdateEntry]>,
+) {
+    for entity in &mut vehicle_positions.entity {
+        let Some(vehicle) = entity.vehicle.as_mut() else {
+            continue;
+        };
+        let entries = vehicle
+            .trip
+            .as_ref()
+            .and_then(|t| t.trip_id.as_deref())
+            .and_then(|id| timetables.get(id));
+        let (Some(entries), Some(position)) = (entries, &vehicle.position) else {
+            continue;
+        };
+        let Some(status) = stop_status(position, entries) else {
+            continue;
+        };
+        vehicle.current_status = Some(status.status as i32);
+        vehicle.current_stop_sequence = Some(status.stop_sequence);
+        vehicle.stop_id = Some(status.stop_id);
+    }
+}
+
+pub(crate) fn full_dataset_header() -> FeedHeader {
     let timestamp = SystemTime::now()
         .duration_since(UNIX_EPOCH)
         .unwrap()
         .as_secs();
 
-    let entities = vehicles
-        .into_iter()
-        .map(|v| {
-            let trip_desc = if let Some(trip) = v.trip {
-                Some(TripDescriptor {
-                    trip_id: Some(strip_prefix(&trip.id)),
-                    route_id: v.route.map(|r| strip_prefix(&r.id)),
-                    direction_id: v.direction_id.map(|d| d as u32),
-                    start_time: None,
-                    start_date: None,
-                    schedule_relationship: None,
-                    modified_trip: None,
-                })
-            } else {
-                None
-            };
+    FeedHeader {
+        gtfs_realtime_version: "2.0".to_string(),
+        incrementality: Some(Incrementality::FullDataset as i32),
+        timestamp: Some(timestamp),
+        feed_version: None,
+    }
+}
 
-            let position = Position {
-                latitude: v.coordinate.lat as f32,
-                longitude: v.coordinate.lng as f32,
-                bearing: v.orientation.map(|o| o as f32),
-                odometer: None,
-                speed: None,
-            };
+/// How to publish stops for which Unwire only returned scheduled times.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ScheduleOnlyStops {
+    /// Leave the stop out of the trip update entirely.
+    Omit,
+    /// Keep the stop with `ScheduleRelationship::NoData` and no arrival or departure.
+    #[default]
+    NoData,
+}
 
-            let vehicle_id = strip_prefix(&v.id);
-            let vehicle_desc = VehicleDescriptor {
-                id: Some(vehicle_id.clone()),
-                label: v.head_sign,
-                license_plate: None,
-                wheelchair_accessible: None,
-            };
+/// Per-feed knobs for turning Unwire timetables into GTFS-RT.
+#[derive(Debug, Clone)]
+pub struct ConversionOptions {
+    pub schedule_only_stops: ScheduleOnlyStops,
+    /// Timezone used to derive `start_date` and `start_time`; both are left unset without one.
+    pub timezone: Option<Tz>,
+    pub service_day_cutoff_hour: u32,
+}
 
-            let vehicle_pos = VehiclePosition {
-                trip: trip_desc,
-                vehicle: Some(vehicle_desc),
-                position: Some(position),
-                current_stop_sequence: None,
-                stop_id: v.stop.map(|s| strip_prefix(&s.id)),
-                current_status: None,
-                timestamp: None,
-                congestion_level: None,
-                occupancy_status: None,
-                occupancy_percentage: None,
-                multi_carriage_details: Vec::new(),
-            };
+impl Default for ConversionOptions {
+    fn default() -> Self {
+        Self {
+            schedule_only_stops: ScheduleOnlyStops::default(),
+            timezone: None,
+            service_day_cutoff_hour: 3,
+        }
+    }
+}
 
-            FeedEntity {
-                id: vehicle_id,
-                is_deleted: Some(false),
-                trip_update: None,
-                vehicle: Some(vehicle_pos),
-                alert: None,
-                shape: None,
-                stop: None,
-                trip_modifications: None,
-            }
-        })
-        .collect();
+impl ConversionOptions {
+    /// Options configured on `feed`'s agency, or the defaults for unknown feeds.
+    pub fn for_feed(feed: FeedId) -> Self {
+        let Some(agency) = agency::registry().get(feed).cloned() else {
+            return Self::default();
+        };
 
-    FeedMessage {
-        header: FeedHeader {
-            gtfs_realtime_version: "2.0".to_string(),
-            incrementality: Some(Incrementality::FullDataset as i32),
-            timestamp: Some(timestamp),
-            feed_version: None,
-        },
-        entity: entities,
+        Self {
+            schedule_only_stops: agency.schedule_only_stops,
+            // Registration refuses agencies whose timezone does not parse.
+            timezone: agency.tz().ok(),
+            service_day_cutoff_hour: agency.service_day_cutoff_hour,
+        }
     }
 }
 
-fn parse_time(t: &Option<String>) -> Option<i64> {
-    if let Some(s) = t {
-        if let Ok(dt) = DateTime::parse_from_rfc3339(s) {
-            return Some(dt.timestamp());
+/// GTFS `start_date` (`YYYYMMDD`) and `start_time` (`HH:MM:SS`) for a trip departing at `local`.
+///
+/// Departures before `cutoff_hour` are attributed to the previous service day, so 01:10 on
+/// the 2nd becomes `25:10:00` on the 1st.
+pub fn service_start(local: NaiveDateTime, cutoff_hour: u32) -> (String, String) {
+    let (date, hour) = if local.hour() < cutoff_hour {
+        match local.date().pred_opt() {
+            Some(previous) => (previous, local.hour() + 24),
+            None => (local.date(), local.hour()),
         }
+    } else {
+        (local.date(), local.hour())
+    };
+
+    (
+        date.format("%Y%m%d").to_string(),
+        format!("{:02}:{:02}:{:02}", hour, local.minute(), local.second()),
+    )
+}
+
+/// Start date and time from the scheduled departure at the first stop of the timetable, or
+/// `None` when the options have no timezone to express them in.
+pub(crate) fn trip_start(
+    entries: &[TripUpdateEntry],
+    options: &ConversionOptions,
+) -> Option<(String, String)> {
+    let timezone = options.timezone?;
+    let first = entries.iter().min_by_key(|entry| entry.stop.index)?;
+    let scheduled = [&first.departure, &first.arrival]
+        .into_iter()
+        .flatten()
+        .find_map(|info| info.scheduled.as_deref())?;
+
+    let local = DateTime::parse_from_rfc3339(scheduled)
+        .ok()?
+        .with_timezone(&timezone);
+    Some(service_start(
+        local.naive_local(),
+        options.service_day_cutoff_hour,
+    ))
+}
+
+fn parse_time(t: &Option<String>) -> Option<i64> {
+    if let Some(s) = t
+        && let Ok(dt) = DateTime::parse_from_rfc3339(s)
+    {
+        return Some(dt.timestamp());
     }
     None
 }
 
+/// A prediction for one arrival or departure; `None` unless Unwire sent a real-time value
+/// that its state does not mark as merely scheduled.
+fn stop_time_event(info: &TimeInfo) -> Option<StopTimeEvent> {
+    if matches!(info.state, Some(TimeState::Scheduled | TimeState::Skipped)) {
+        return None;
+    }
+    let real = parse_time(&info.real)?;
+    let scheduled = parse_time(&info.scheduled);
+
+    Some(StopTimeEvent {
+        delay: scheduled.map(|s| (real - s) as i32),
+        time: Some(real),
+        uncertainty: None,
+        scheduled_time: scheduled,
+    })
+}
+
+/// Converts a timetable with default options. No timezone is known here, so `start_date` and
+/// `start_time` stay unset; pass [`ConversionOptions::for_feed`] to
+/// [`convert_trip_update_for_vehicle`] to get them.
 pub fn convert_trip_update(trip_id: String, update: TripUpdateResponse) -> TripUpdate {
-    let stop_time_updates = update
-        .entries
+    convert_trip_update_for_vehicle(trip_id, update, None, &ConversionOptions::default())
+}
+
+/// Like [`convert_trip_update`], but fills the vehicle, route and direction from the
+/// snapshot entry of the vehicle running the trip.
+pub fn convert_trip_update_for_vehicle(
+    trip_id: String,
+    update: TripUpdateResponse,
+    vehicle: Option<&VehicleContent>,
+    options: &ConversionOptions,
+) -> TripUpdate {
+    let (start_date, start_time) = match trip_start(&update.entries, options) {
+        Some((date, time)) => (Some(date), Some(time)),
+        None => (None, None),
+    };
+
+    let trip_relationship = match &update.state {
+        Some(TripState::Cancelled) => Some(TripScheduleRelationship::Canceled as i32),
+        // ADDED is deprecated in GTFS-RT; NEW is its replacement.
+        Some(TripState::Added) => Some(TripScheduleRelationship::New as i32),
+        Some(TripState::Scheduled | TripState::Realtime) => {
+            Some(TripScheduleRelationship::Scheduled as i32)
+        }
+        Some(TripState::Unknown(_)) | None => None,
+    };
+
+    // Cancelled trips carry no stop time updates.
+    let entries = match update.state {
+        Some(TripState::Cancelled) => Vec::new(),
+        _ => update.entries,
+    };
+
+    let stop_time_updates = entries
         .into_iter()
-        .map(|entry| {
-            let arrival = if let Some(arr) = entry.arrival {
-                Some(StopTimeEvent {
-                    delay: None,
-                    time: parse_time(&arr.real).or_else(|| parse_time(&arr.scheduled)),
-                    uncertainty: None,
-                    scheduled_time: None,
-                })
-            } else {
-                None
-            };
+        .filter_map(|entry| {
+            let skipped = [&entry.arrival, &entry.departure]
+                .into_iter()
+                .flatten()
+                .any(|info| info.state == Some(TimeState::Skipped));
+            if skipped {
+                return Some(StopTimeUpdate {
+                    stop_sequence: Some(entry.stop.index),
+                    stop_id: Some(strip_prefix(&entry.stop.id)),
+                    arrival: None,
+                    departure: None,
+                    departure_occupancy_status: None,
+                    schedule_relationship: Some(ScheduleRelationship::Skipped as i32),
+                    stop_time_properties: None,
+                });
+            }
 
-            let departure = if let Some(dep) = entry.departure {
-                Some(StopTimeEvent {
-                    delay: None,
-                    time: parse_time(&dep.real).or_else(|| parse_time(&dep.scheduled)),
-                    uncertainty: None,
-                    scheduled_time: None,
-                })
+            let arrival = entry.arrival.as_ref().and_then(stop_time_event);
+            let departure = entry.departure.as_ref().and_then(stop_time_event);
+
+            let schedule_relationship = if arrival.is_none() && departure.is_none() {
+                match options.schedule_only_stops {
+                    ScheduleOnlyStops::Omit => return None,
+                    ScheduleOnlyStops::NoData => Some(ScheduleRelationship::NoData as i32),
+                }
             } else {
                 None
             };
 
-            StopTimeUpdate {
+            Some(StopTimeUpdate {
                 stop_sequence: Some(entry.stop.index),
                 stop_id: Some(strip_prefix(&entry.stop.id)),
                 arrival,
                 departure,
                 departure_occupancy_status: None,
-                schedule_relationship: None,
+                schedule_relationship,
                 stop_time_properties: None,
-            }
+            })
         })
         .collect();
 
     TripUpdate {
         trip: TripDescriptor {
             trip_id: Some(strip_prefix(&trip_id)),
-            route_id: None, // Not available in TripUpdateResponse
-            direction_id: None,
-            start_time: None,
-            start_date: None,
-            schedule_relationship: None,
+            route_id: vehicle
+                .and_then(|v| v.route.as_ref())
+                .map(|r| strip_prefix(&r.id)),
+            direction_id: vehicle.and_then(|v| v.direction_id).map(|d| d as u32),
+            start_time,
+            start_date,
+            schedule_relationship: trip_relationship,
             modified_trip: None,
         },
-        vehicle: None,
+        vehicle: vehicle.map(vehicle_descriptor),
         stop_time_update: stop_time_updates,
         timestamp: None,
         delay: None,
         trip_properties: None,
     }
 }
+
+/// Copies `start_date` and `start_time` from trip updates onto the vehicles running those
+/// trips, since the vehicle snapshot alone does not say when a trip started.
+pub fn fill_trip_starts(vehicle_positions: &mut FeedMessage, trip_updates: &FeedMessage) {
+    let starts: HashMap<&str, &TripDescriptor> = trip_updates
+        .entity
+        .iter()
+        .filter_map(|e| e.trip_update.as_ref())
+        .filter_map(|tu| Some((tu.trip.trip_id.as_deref()?, &tu.trip)))
+        .collect();
+
+    for entity in &mut vehicle_positions.entity {
+        let Some(trip) = entity.vehicle.as_mut().and_then(|v| v.trip.as_mut()) else {
+            continue;
+        };
+        let Some(source) = trip.trip_id.as_deref().and_then(|id| starts.get(id)) else {
+            continue;
+        };
+        trip.start_date = source.start_date.clone();
+        trip.start_time = source.start_time.clone();
+    }
+}
+
+/// Entity id prefix for trip updates published without a vehicle in a combined feed.
+const STANDALONE_TRIP_PREFIX: &str = "trip:";
+
+/// Merges vehicle positions and trip updates into one feed where each vehicle's entity
+/// also carries the update for the trip it is running.
+///
+/// Trip updates without a matching vehicle are kept as standalone entities, with their id
+/// prefixed by `trip:` so it cannot collide with a vehicle id (`DART-1234` and `DART:1234`
+/// both strip to `1234`).
+pub fn combine_feeds(vehicle_positions: &FeedMessage, trip_updates: &FeedMessage) -> FeedMessage {
+    let mut updates_by_trip: HashMap<&str, &TripUpdate> = trip_updates
+        .entity
+        .iter()
+        .filter_map(|e| e.trip_update.as_ref())
+        .filter_map(|tu| Some((tu.trip.trip_id.as_deref()?, tu)))
+        .collect();
+
+    let mut entities: Vec<FeedEntity> = vehicle_positions
+        .entity
+        .iter()
+        .map(|entity| {
+            let trip_id = entity
+                .vehicle
+                .as_ref()
+                .and_then(|v| v.trip.as_ref())
+                .and_then(|t| t.trip_id.as_deref());
+            let trip_update = trip_id.and_then(|id| updates_by_trip.remove(id));
+            FeedEntity {
+                trip_update: trip_update.cloned(),
+                ..entity.clone()
+            }
+        })
+        .collect();
+
+    entities.extend(
+        trip_updates
+            .entity
+            .iter()
+            .filter(|entity| {
+                entity
+                    .trip_update
+                    .as_ref()
+                    .and_then(|tu| tu.trip.trip_id.as_deref())
+                    .is_some_and(|id| updates_by_trip.contains_key(id))
+            })
+            .map(|entity| FeedEntity {
+                id: format!("{}{}", STANDALONE_TRIP_PREFIX, entity.id),
+                ..entity.clone()
+            }),
+    );
+
+    FeedMessage {
+        header: full_dataset_header(),
+        entity: entities,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::model::{Coordinate, StopInfo, TripUpdateEntry};
+    use chrono::NaiveDate;
+
+    fn entry(id: &str, index: u32, lng: f64) -> TripUpdateEntry {
+        TripUpdateEntry {
+            stop: StopInfo {
+                id: format!("DART:{}", id),
+                name: None,
+                index,
+                coordinate: Some(Coordinate { lat: 32.0, lng }),
+            },
+            arrival: None,
+            departure: None,
+        }
+    }
+
+    fn chicago() -> ConversionOptions {
+        ConversionOptions {
+            timezone: Some(chrono_tz::America::Chicago),
+            ..ConversionOptions::default()
+        }
+    }
+
+    fn time(state: &str, scheduled: &str, real: Option<&str>) -> Option<TimeInfo> {
+        Some(TimeInfo {
+            state: Some(TimeState::from(state.to_string())),
+            scheduled: Some(scheduled.to_string()),
+            real: real.map(str::to_string),
+        })
+    }
+
+    #[test]
+    fn service_start_moves_early_departures_to_the_previous_day() {
+        let at = |h, m, s| {
+            NaiveDate::from_ymd_opt(2024, 3, 2)
+                .unwrap()
+                .and_hms_opt(h, m, s)
+                .unwrap()
+        };
+        assert_eq!(
+            service_start(at(1, 10, 0), 3),
+            ("20240301".to_string(), "25:10:00".to_string())
+        );
+        assert_eq!(
+            service_start(at(8, 5, 9), 3),
+            ("20240302".to_string(), "08:05:09".to_string())
+        );
+        assert_eq!(
+            service_start(at(1, 10, 0), 0),
+            ("20240302".to_string(), "01:10:00".to_string())
+        );
+    }
+
+    #[test]
+    fn trip_start_uses_the_first_stop_in_the_agency_timezone() {
+        let mut first = entry("S1", 1, -97.0);
+        first.departure = time("SCHEDULED", "2024-03-02T07:10:00Z", None);
+        let mut second = entry("S2", 2, -96.99);
+        second.departure = time("SCHEDULED", "2024-03-02T07:30:00Z", None);
+        let entries = [second, first];
+
+        assert_eq!(
+            trip_start(&entries, &chicago()),
+            Some(("20240301".to_string(), "25:10:00".to_string()))
+        );
+        // Without a timezone the start cannot be expressed in local time.
+        assert_eq!(trip_start(&entries, &ConversionOptions::default()), None);
+    }
+
+    #[test]
+    fn maps_trip_and_time_states() {
+        let mut skipped = entry("S1", 1, -97.0);
+        skipped.arrival = time("CANCELLED", "2024-03-02T14:00:00Z", None);
+        let mut predicted = entry("S2", 2, -96.99);
+        predicted.arrival = time(
+            "PREDICTED",
+            "2024-03-02T14:10:00Z",
+            Some("2024-03-02T14:12:00Z"),
+        );
+        let mut scheduled = entry("S3", 3, -96.98);
+        scheduled.arrival = time(
+            "SCHEDULED",
+            "2024-03-02T14:20:00Z",
+            Some("2024-03-02T14:20:00Z"),
+        );
+        let update = |state: &str| TripUpdateResponse {
+            state: Some(TripState::from(state.to_string())),
+            entries: vec![skipped.clone(), predicted.clone(), scheduled.clone()],
+        };
+        let convert = |state: &str| {
+            convert_trip_update_for_vehicle(
+                "DART:1".to_string(),
+                update(state),
+                None,
+                &ConversionOptions::default(),
+            )
+        };
+
+        let realtime = convert("REALTIME");
+        assert_eq!(
+            realtime.trip.schedule_relationship,
+            Some(TripScheduleRelationship::Scheduled as i32)
+        );
+        let stops = &realtime.stop_time_update;
+        assert_eq!(
+            stops[0].schedule_relationship,
+            Some(ScheduleRelationship::Skipped as i32)
+        );
+        let arrival = stops[1].arrival.unwrap();
+        assert_eq!(arrival.delay, Some(120));
+        assert_eq!(
+            stops[2].schedule_relationship,
+            Some(ScheduleRelationship::NoData as i32)
+        );
+        assert!(stops[2].arrival.is_none());
+
+        let cancelled = convert("canceled");
+        assert_eq!(
+            cancelled.trip.schedule_relationship,
+            Some(TripScheduleRelationship::Canceled as i32)
+        );
+        assert!(cancelled.stop_time_update.is_empty());
+
+        assert_eq!(
+            convert("ADDED").trip.schedule_relationship,
+            Some(TripScheduleRelationship::New as i32)
+        );
+        assert_eq!(convert("detoured").trip.schedule_relationship, None);
+    }
+
+    #[test]
+    fn default_conversion_leaves_trip_start_unset() {
+        let mut first = entry("S1", 1, -97.0);
+        first.departure = time("SCHEDULED", "2024-03-02T07:10:00Z", None);
+        let update = TripUpdateResponse {
+            state: None,
+            entries: vec![first],
+        };
+        let trip_update = convert_trip_update("DART:1".to_string(), update);
+        assert_eq!(trip_update.trip.start_date, None);
+        assert_eq!(trip_update.trip.start_time, None);
+    }
+
+    /// Stops 1-3 lie on latitude 32 about 943 m apart.
+    fn line() -> Vec<TripUpdateEntry> {
+        vec![
+            entry("S1", 1, -97.0),
+            entry("S2", 2, -96.99),
+            entry("S3", 3, -96.98),
+        ]
+    }
+
+    fn status_at(lng: f64) -> Option<StopStatus> {
+        let position = Position {
+            latitude: 32.0,
+            longitude: lng as f32,
+            ..Default::default()
+        };
+        stop_status(&position, &line())
+    }
+
+    #[test]
+    fn stop_status_follows_the_vehicle_along_the_line() {
+        let at_stop = status_at(-96.99).unwrap();
+        assert_eq!(at_stop.status, VehicleStopStatus::StoppedAt);
+        assert_eq!(at_stop.stop_id, "S2");
+        assert_eq!(at_stop.stop_sequence, 2);
+
+        // About 100 m before stop 2.
+        let approaching = status_at(-96.99106).unwrap();
+        assert_eq!(approaching.status, VehicleStopStatus::IncomingAt);
+        assert_eq!(approaching.stop_id, "S2");
+
+        // About 300 m past stop 2, so it is heading to stop 3.
+        let departed = status_at(-96.98682).unwrap();
+        assert_eq!(departed.status, VehicleStopStatus::InTransitTo);
+        assert_eq!(departed.stop_id, "S3");
+    }
+
+    #[test]
+    fn stop_status_needs_stop_coordinates() {
+        let mut entries = line();
+        for entry in &mut entries {
+            entry.stop.coordinate = None;
+        }
+        let position = Position {
+            latitude: 32.0,
+            longitude: -96.99,
+            ..Default::default()
+        };
+        assert!(stop_status(&position, &entries).is_none());
+    }
+}
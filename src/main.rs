@@ -1,13 +1,47 @@
-use unwire_gtfs_rt::{FeedId, fetch_all_feed_trip_updates, fetch_feed_vehicles};
+use std::net::SocketAddr;
+use std::time::Duration;
+use unwire_gtfs_rt::{FeedFetcher, FeedId, Poller, server};
+
+const DEFAULT_ADDR: &str = "0.0.0.0:8080";
+const POLL_INTERVAL: Duration = Duration::from_secs(15);
 
 #[tokio::main]
 async fn main() {
     env_logger::init();
 
-    let feed = FeedId::Dart;
+    let fetcher = match FeedFetcher::new().await {
+        Ok(fetcher) => fetcher,
+        Err(e) => {
+            eprintln!("Error creating client: {:#}", e);
+            return;
+        }
+    };
+
+    let mut args = std::env::args().skip(1);
+    match args.next().as_deref() {
+        Some("serve") => {
+            let addr: SocketAddr = match args.next().as_deref().unwrap_or(DEFAULT_ADDR).parse() {
+                Ok(addr) => addr,
+                Err(e) => {
+                    eprintln!("Invalid listen address: {}", e);
+                    return;
+                }
+            };
+            let poller = Poller::new(fetcher, FeedId::all(), POLL_INTERVAL);
+            poller.spawn();
+            if let Err(e) = server::serve(addr, poller).await {
+                eprintln!("Server failed: {:#}", e);
+            }
+        }
+        _ => demo(fetcher).await,
+    }
+}
+
+async fn demo(fetcher: FeedFetcher) {
+    let feed = FeedId::DART;
 
     println!("--- Fetching Vehicles ---");
-    match fetch_feed_vehicles(feed).await {
+    match fetcher.fetch_feed_vehicles(feed).await {
         Ok(feed) => {
             println!("Successfully fetched {} vehicles", feed.entity.len());
             for entity in feed.entity.iter().take(5) {
@@ -18,7 +52,7 @@ async fn main() {
     }
 
     println!("\n--- Fetching All Trip Updates ---");
-    match fetch_all_feed_trip_updates(feed).await {
+    match fetcher.fetch_all_feed_trip_updates(feed).await {
         Ok(feed) => {
             println!("Successfully fetched {} trip updates", feed.entity.len());
             for entity in feed.entity.iter().take(5) {
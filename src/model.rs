@@ -1,11 +1,11 @@
-use serde::{Deserialize, Serialize};
+use serde::{Deserialize, Deserializer, Serialize};
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct VehicleSnapshotResponse {
     pub content: Vec<VehicleContent>,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct VehicleContent {
     pub id: String,
@@ -18,28 +18,67 @@ pub struct VehicleContent {
     pub short_code: Option<String>,
     pub head_sign: Option<String>,
     pub direction_id: Option<i32>,
+    /// When Unwire last received this position, if the snapshot says.
+    #[serde(default, deserialize_with = "lenient_upstream_time")]
+    pub timestamp: Option<UpstreamTime>,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+/// Reads a timestamp of unknown shape as `None` rather than failing the whole snapshot.
+fn lenient_upstream_time<'de, D>(deserializer: D) -> Result<Option<UpstreamTime>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let time = match serde_json::Value::deserialize(deserializer)? {
+        serde_json::Value::Number(n) => n
+            .as_i64()
+            .or_else(|| n.as_f64().map(|f| f as i64))
+            .map(UpstreamTime::Epoch),
+        serde_json::Value::String(s) => Some(UpstreamTime::Text(s)),
+        _ => None,
+    };
+    Ok(time)
+}
+
+/// A time as Unwire may send it: an RFC 3339 string or a Unix epoch in seconds or millis.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum UpstreamTime {
+    Epoch(i64),
+    Text(String),
+}
+
+impl UpstreamTime {
+    pub fn unix_seconds(&self) -> Option<u64> {
+        let seconds = match self {
+            // Anything past the year 33658 in seconds is really milliseconds.
+            UpstreamTime::Epoch(n) if *n > 1_000_000_000_000 => n / 1000,
+            UpstreamTime::Epoch(n) => *n,
+            UpstreamTime::Text(s) => chrono::DateTime::parse_from_rfc3339(s).ok()?.timestamp(),
+        };
+        u64::try_from(seconds).ok()
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Coordinate {
     pub lat: f64,
     pub lng: f64,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct EntityRef {
     pub id: String,
     pub feed_id: String,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct TripUpdateResponse {
-    pub state: Option<String>,
+    pub state: Option<TripState>,
     pub entries: Vec<TripUpdateEntry>,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct TripUpdateEntry {
     pub stop: StopInfo,
@@ -47,7 +86,7 @@ pub struct TripUpdateEntry {
     pub departure: Option<TimeInfo>,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct StopInfo {
     pub id: String,
@@ -56,10 +95,161 @@ pub struct StopInfo {
     pub coordinate: Option<Coordinate>,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct TimeInfo {
-    pub state: Option<String>,
+    pub state: Option<TimeState>,
     pub scheduled: Option<String>,
     pub real: Option<String>,
 }
+
+/// Status of a whole trip in a timetable response.
+///
+/// Matching is case-insensitive; unrecognised values are kept in `Unknown`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(from = "String", into = "String")]
+pub enum TripState {
+    Scheduled,
+    Realtime,
+    Cancelled,
+    Added,
+    Unknown(String),
+}
+
+impl From<String> for TripState {
+    fn from(value: String) -> Self {
+        match value.to_ascii_uppercase().as_str() {
+            "SCHEDULED" | "PLANNED" => TripState::Scheduled,
+            "REAL" | "REALTIME" | "REAL_TIME" | "UPDATED" | "RUNNING" => TripState::Realtime,
+            "CANCELLED" | "CANCELED" => TripState::Cancelled,
+            "ADDED" | "NEW" | "EXTRA" => TripState::Added,
+            _ => TripState::Unknown(value),
+        }
+    }
+}
+
+impl From<TripState> for String {
+    fn from(state: TripState) -> Self {
+        match state {
+            TripState::Scheduled => "SCHEDULED".to_string(),
+            TripState::Realtime => "REALTIME".to_string(),
+            TripState::Cancelled => "CANCELLED".to_string(),
+            TripState::Added => "ADDED".to_string(),
+            TripState::Unknown(value) => value,
+        }
+    }
+}
+
+/// Status of a single arrival or departure.
+///
+/// Matching is case-insensitive; unrecognised values are kept in `Unknown`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(from = "String", into = "String")]
+pub enum TimeState {
+    Scheduled,
+    Real,
+    Skipped,
+    Unknown(String),
+}
+
+impl From<String> for TimeState {
+    fn from(value: String) -> Self {
+        match value.to_ascii_uppercase().as_str() {
+            "SCHEDULED" | "PLANNED" => TimeState::Scheduled,
+            "REAL" | "REALTIME" | "REAL_TIME" | "PREDICTED" | "ESTIMATED" | "UPDATED" => {
+                TimeState::Real
+            }
+            "SKIPPED" | "CANCELLED" | "CANCELED" => TimeState::Skipped,
+            _ => TimeState::Unknown(value),
+        }
+    }
+}
+
+impl From<TimeState> for String {
+    fn from(state: TimeState) -> Self {
+        match state {
+            TimeState::Scheduled => "SCHEDULED".to_string(),
+            TimeState::Real => "REAL".to_string(),
+            TimeState::Skipped => "SKIPPED".to_string(),
+            TimeState::Unknown(value) => value,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn snapshot(timestamp: &str) -> VehicleSnapshotResponse {
+        let json = format!(
+            r#"{{"content":[{{"id":"DART-1","coordinate":{{"lat":32.7,"lng":-96.8}}{}}}]}}"#,
+            timestamp
+        );
+        serde_json::from_str(&json).unwrap()
+    }
+
+    fn unix_seconds(timestamp: &str) -> Option<u64> {
+        snapshot(timestamp).content[0]
+            .timestamp
+            .as_ref()
+            .and_then(UpstreamTime::unix_seconds)
+    }
+
+    #[test]
+    fn reads_epoch_seconds_millis_and_rfc3339() {
+        assert_eq!(
+            unix_seconds(r#","timestamp":1700000000"#),
+            Some(1_700_000_000)
+        );
+        assert_eq!(
+            unix_seconds(r#","timestamp":1700000000123"#),
+            Some(1_700_000_000)
+        );
+        assert_eq!(
+            unix_seconds(r#","timestamp":"2023-11-14T22:13:20Z""#),
+            Some(1_700_000_000)
+        );
+        assert_eq!(unix_seconds(""), None);
+    }
+
+    #[test]
+    fn unexpected_timestamps_do_not_fail_the_snapshot() {
+        assert_eq!(
+            unix_seconds(r#","timestamp":1700000000.5"#),
+            Some(1_700_000_000)
+        );
+        assert_eq!(unix_seconds(r#","timestamp":{"seconds":1}"#), None);
+        assert_eq!(unix_seconds(r#","timestamp":null"#), None);
+        assert_eq!(unix_seconds(r#","timestamp":"yesterday""#), None);
+        // Fields the gateway is not known to send are ignored.
+        assert_eq!(
+            unix_seconds(r#","timestamp":1700000000,"lastUpdate":1"#),
+            Some(1_700_000_000)
+        );
+    }
+
+    #[test]
+    fn trip_states_match_case_insensitively() {
+        let state = |value: &str| TripState::from(value.to_string());
+        assert_eq!(state("scheduled"), TripState::Scheduled);
+        assert_eq!(state("PLANNED"), TripState::Scheduled);
+        assert_eq!(state("Real_Time"), TripState::Realtime);
+        assert_eq!(state("canceled"), TripState::Cancelled);
+        assert_eq!(state("EXTRA"), TripState::Added);
+        assert_eq!(
+            state("detoured"),
+            TripState::Unknown("detoured".to_string())
+        );
+        assert_eq!(String::from(state("detoured")), "detoured");
+    }
+
+    #[test]
+    fn time_states_match_case_insensitively() {
+        let state = |value: &str| TimeState::from(value.to_string());
+        assert_eq!(state("planned"), TimeState::Scheduled);
+        assert_eq!(state("Predicted"), TimeState::Real);
+        assert_eq!(state("ESTIMATED"), TimeState::Real);
+        assert_eq!(state("cancelled"), TimeState::Skipped);
+        assert_eq!(state("late"), TimeState::Unknown("late".to_string()));
+    }
+}
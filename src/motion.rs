@@ -0,0 +1,229 @@
+use crate::gtfs::{FeedMessage, distance_meters};
+use crate::model::Coordinate;
+use std::collections::HashMap;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Faster than this between two samples is treated as a GPS jump, not movement (~145 km/h).
+const MAX_PLAUSIBLE_SPEED: f64 = 40.0;
+/// Movement shorter than this is GPS noise and too short to take a bearing from.
+const MIN_BEARING_DISTANCE: f64 = 10.0;
+/// Consecutive jumps after which the new position is accepted as the vehicle's real location.
+const MAX_REJECTED_JUMPS: u32 = 2;
+/// A vehicle whose coordinate has not changed for this long is reported as standing still.
+const STATIONARY_AFTER: Duration = Duration::from_secs(60);
+/// Vehicles not seen for this long are forgotten; speed over a longer gap says little.
+const MAX_SAMPLE_AGE: Duration = Duration::from_secs(300);
+
+#[derive(Debug, Clone)]
+struct Sample {
+    coordinate: Coordinate,
+    /// When the vehicle reached `coordinate`; speed is measured from here.
+    at: SystemTime,
+    /// Latest observation, moved or not; eviction goes by this.
+    last_seen: SystemTime,
+    speed: Option<f32>,
+    bearing: Option<f32>,
+    rejected_jumps: u32,
+}
+
+/// Estimates speed and bearing for each vehicle from the positions seen on successive polls.
+#[derive(Debug, Default)]
+pub struct MotionTracker {
+    samples: HashMap<String, Sample>,
+}
+
+impl MotionTracker {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Records every vehicle position in `message`, observed at `at` unless the vehicle
+    /// carries its own `timestamp`, and fills in `speed` (m/s) and any missing `bearing` from
+    /// the vehicle's previous position.
+    pub fn update(&mut self, message: &mut FeedMessage, at: SystemTime) {
+        self.samples.retain(|_, sample| {
+            at.duration_since(sample.last_seen)
+                .is_ok_and(|age| age <= MAX_SAMPLE_AGE)
+        });
+
+        for entity in &mut message.entity {
+            let Some(vehicle) = entity.vehicle.as_mut() else {
+                continue;
+            };
+            let Some(position) = vehicle.position.as_mut() else {
+                continue;
+            };
+            let id = vehicle
+                .vehicle
+                .as_ref()
+                .and_then(|v| v.id.clone())
+                .unwrap_or_else(|| entity.id.clone());
+            let coordinate = Coordinate {
+                lat: position.latitude as f64,
+                lng: position.longitude as f64,
+            };
+
+            let observed_at = vehicle
+                .timestamp
+                .map_or(at, |t| UNIX_EPOCH + Duration::from_secs(t));
+            let sample = self.observe(id, coordinate, observed_at);
+            if position.speed.is_none() {
+                position.speed = sample.speed;
+            }
+            if position.bearing.is_none() {
+                position.bearing = sample.bearing;
+            }
+        }
+    }
+
+    fn observe(&mut self, id: String, coordinate: Coordinate, at: SystemTime) -> &Sample {
+        let fresh = |coordinate| Sample {
+            coordinate,
+            at,
+            last_seen: at,
+            speed: None,
+            bearing: None,
+            rejected_jumps: 0,
+        };
+
+        let sample = self
+            .samples
+            .entry(id)
+            .or_insert_with(|| fresh(coordinate.clone()));
+        sample.last_seen = sample.last_seen.max(at);
+        let Ok(elapsed) = at.duration_since(sample.at) else {
+            return sample;
+        };
+        let distance = distance_meters(&sample.coordinate, &coordinate);
+
+        // Unwire often repeats a position for a few polls; keep the old sample so the next
+        // real move is measured over the whole interval.
+        if distance < MIN_BEARING_DISTANCE {
+            if elapsed >= STATIONARY_AFTER {
+                sample.speed = Some(0.0);
+            }
+            return sample;
+        }
+        if elapsed.is_zero() {
+            return sample;
+        }
+
+        let speed = distance / elapsed.as_secs_f64();
+        if speed > MAX_PLAUSIBLE_SPEED {
+            sample.rejected_jumps += 1;
+            if sample.rejected_jumps > MAX_REJECTED_JUMPS {
+                *sample = fresh(coordinate);
+            }
+            return sample;
+        }
+
+        sample.bearing = Some(bearing_degrees(&sample.coordinate, &coordinate) as f32);
+        sample.speed = Some(speed as f32);
+        sample.coordinate = coordinate;
+        sample.at = at;
+        sample.rejected_jumps = 0;
+        sample
+    }
+}
+
+/// Initial great-circle bearing from `a` to `b`, clockwise from true north in `[0, 360)`.
+fn bearing_degrees(a: &Coordinate, b: &Coordinate) -> f64 {
+    let (lat_a, lat_b) = (a.lat.to_radians(), b.lat.to_radians());
+    let d_lng = (b.lng - a.lng).to_radians();
+    let y = d_lng.sin() * lat_b.cos();
+    let x = lat_a.cos() * lat_b.sin() - lat_a.sin() * lat_b.cos() * d_lng.cos();
+    y.atan2(x).to_degrees().rem_euclid(360.0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::gtfs::{FeedEntity, Position, VehiclePosition};
+
+    const LAT: f64 = 32.0;
+
+    fn at(secs: u64) -> SystemTime {
+        UNIX_EPOCH + Duration::from_secs(1_700_000_000 + secs)
+    }
+
+    fn coordinate(lng: f64) -> Coordinate {
+        Coordinate { lat: LAT, lng }
+    }
+
+    /// Polls one vehicle at `lng` and returns the speed filled in for it.
+    fn poll(tracker: &mut MotionTracker, lng: f64, secs: u64) -> Option<f32> {
+        let mut message = FeedMessage {
+            entity: vec![FeedEntity {
+                id: "bus".to_string(),
+                vehicle: Some(VehiclePosition {
+                    position: Some(Position {
+                        latitude: LAT as f32,
+                        longitude: lng as f32,
+                        ..Default::default()
+                    }),
+                    ..Default::default()
+                }),
+                ..Default::default()
+            }],
+            ..Default::default()
+        };
+        tracker.update(&mut message, at(secs));
+        message.entity[0].vehicle.as_ref()?.position.as_ref()?.speed
+    }
+
+    fn assert_speed(speed: Option<f32>, from: f64, to: f64, secs: f64) {
+        let expected = distance_meters(&coordinate(from), &coordinate(to)) / secs;
+        let speed = speed.expect("speed is estimated") as f64;
+        assert!(
+            (speed - expected).abs() < 0.1,
+            "speed {speed} != {expected}"
+        );
+    }
+
+    #[test]
+    fn gps_jumps_are_ignored() {
+        let mut tracker = MotionTracker::new();
+        assert_eq!(poll(&mut tracker, -97.0, 0), None);
+        assert_speed(poll(&mut tracker, -96.997, 30), -97.0, -96.997, 30.0);
+
+        // ~9 km in 30 s: the previous estimate stands.
+        assert_speed(poll(&mut tracker, -96.9, 60), -97.0, -96.997, 30.0);
+
+        // The next real move is measured from the last accepted position.
+        assert_speed(poll(&mut tracker, -96.994, 90), -96.997, -96.994, 60.0);
+    }
+
+    #[test]
+    fn repeated_jumps_are_accepted_as_the_new_position() {
+        let mut tracker = MotionTracker::new();
+        poll(&mut tracker, -97.0, 0);
+        poll(&mut tracker, -96.997, 30);
+
+        for secs in [60, 90] {
+            assert!(poll(&mut tracker, -96.9, secs).is_some());
+        }
+        // One jump more than MAX_REJECTED_JUMPS starts over at the new position.
+        assert_eq!(poll(&mut tracker, -96.9, 120), None);
+        assert_speed(poll(&mut tracker, -96.897, 150), -96.9, -96.897, 30.0);
+    }
+
+    #[test]
+    fn unchanged_position_becomes_stationary() {
+        let mut tracker = MotionTracker::new();
+        assert_eq!(poll(&mut tracker, -97.0, 0), None);
+        assert_eq!(poll(&mut tracker, -97.0, 30), None);
+        assert_eq!(poll(&mut tracker, -97.0, 60), Some(0.0));
+    }
+
+    #[test]
+    fn parked_vehicle_outlives_the_sample_age() {
+        let mut tracker = MotionTracker::new();
+        for secs in (0..=420).step_by(60) {
+            poll(&mut tracker, -97.0, secs);
+        }
+        assert_eq!(poll(&mut tracker, -97.0, 450), Some(0.0));
+
+        // Speed is still measured from when the vehicle stopped, not from the last poll.
+        assert_speed(poll(&mut tracker, -96.997, 480), -97.0, -96.997, 480.0);
+    }
+}
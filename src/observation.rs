@@ -0,0 +1,240 @@
+use crate::gtfs::{FeedMessage, distance_meters};
+use crate::model::Coordinate;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeSet, HashMap};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Movement shorter than this is GPS jitter and does not count as a new observation.
+const MIN_MOVE_METERS: f64 = 10.0;
+/// Vehicles missing from the feed are remembered this long, so one gap does not make a
+/// parked vehicle look freshly moved.
+const FORGET_AFTER: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// What to do with vehicles whose position has not changed for longer than the stale threshold.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum StaleVehicles {
+    /// Keep publishing the vehicle; its `timestamp` still shows when it last moved. GTFS-RT
+    /// has no field for this, so only the GeoJSON export marks it, as `stale: true`.
+    Flag,
+    /// Leave the vehicle out of the feed until it moves again.
+    #[default]
+    Drop,
+}
+
+#[derive(Debug, Clone)]
+struct Observation {
+    coordinate: Coordinate,
+    changed_at: SystemTime,
+}
+
+/// Remembers when each vehicle's position last changed, so `VehiclePosition.timestamp` says
+/// when the vehicle was really seen there rather than when the feed was built.
+#[derive(Debug, Default)]
+pub struct ObservationTracker {
+    observations: HashMap<String, Observation>,
+}
+
+impl ObservationTracker {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sets `timestamp` on every vehicle in `message`, polled at `at`, and applies `policy`
+    /// to those unchanged for longer than `stale_after`.
+    ///
+    /// A timestamp sent by Unwire is kept as is. Returns the ids of the stale vehicles.
+    pub fn update(
+        &mut self,
+        message: &mut FeedMessage,
+        at: SystemTime,
+        stale_after: Duration,
+        policy: StaleVehicles,
+    ) -> BTreeSet<String> {
+        let mut seen = HashMap::with_capacity(message.entity.len());
+        let mut stale = BTreeSet::new();
+
+        for entity in &mut message.entity {
+            let Some(vehicle) = entity.vehicle.as_mut() else {
+                continue;
+            };
+            let Some(position) = &vehicle.position else {
+                continue;
+            };
+            let id = vehicle
+                .vehicle
+                .as_ref()
+                .and_then(|v| v.id.clone())
+                .unwrap_or_else(|| entity.id.clone());
+            let coordinate = Coordinate {
+                lat: position.latitude as f64,
+                lng: position.longitude as f64,
+            };
+
+            let changed_at = match (vehicle.timestamp, self.observations.get(&id)) {
+                (Some(upstream), _) => UNIX_EPOCH + Duration::from_secs(upstream),
+                (None, Some(previous))
+                    if distance_meters(&previous.coordinate, &coordinate) < MIN_MOVE_METERS =>
+                {
+                    previous.changed_at
+                }
+                (None, _) => at,
+            };
+            vehicle.timestamp = changed_at
+                .duration_since(UNIX_EPOCH)
+                .ok()
+                .map(|d| d.as_secs());
+
+            if at
+                .duration_since(changed_at)
+                .is_ok_and(|age| age > stale_after)
+            {
+                stale.insert(id.clone());
+            }
+
+            // Keep the coordinate the vehicle was first seen at, so slow drift within the
+            // jitter radius cannot keep a parked vehicle fresh.
+            let coordinate = match self.observations.get(&id) {
+                Some(previous) if previous.changed_at == changed_at => previous.coordinate.clone(),
+                _ => coordinate,
+            };
+            seen.insert(
+                id,
+                Observation {
+                    coordinate,
+                    changed_at,
+                },
+            );
+        }
+
+        if policy == StaleVehicles::Drop && !stale.is_empty() {
+            message.entity.retain(|entity| {
+                let id = entity
+                    .vehicle
+                    .as_ref()
+                    .and_then(|v| v.vehicle.as_ref())
+                    .and_then(|v| v.id.as_ref())
+                    .unwrap_or(&entity.id);
+                !stale.contains(id)
+            });
+        }
+
+        self.observations.retain(|id, observation| {
+            !seen.contains_key(id)
+                && at
+                    .duration_since(observation.changed_at)
+                    .is_ok_and(|age| age <= FORGET_AFTER)
+        });
+        self.observations.extend(seen);
+        stale
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::gtfs::{FeedEntity, Position, VehiclePosition};
+
+    const STALE_AFTER: Duration = Duration::from_secs(120);
+
+    fn at(secs: u64) -> SystemTime {
+        UNIX_EPOCH + Duration::from_secs(1_700_000_000 + secs)
+    }
+
+    /// One vehicle `meters_east` of a fixed point, optionally with an upstream timestamp.
+    fn message(meters_east: f64, timestamp: Option<u64>) -> FeedMessage {
+        // Roughly 94.4 km per degree of longitude at 32°N.
+        let longitude = -97.0 + meters_east / 94_400.0;
+        FeedMessage {
+            entity: vec![FeedEntity {
+                id: "bus".to_string(),
+                vehicle: Some(VehiclePosition {
+                    position: Some(Position {
+                        latitude: 32.0,
+                        longitude: longitude as f32,
+                        ..Default::default()
+                    }),
+                    timestamp,
+                    ..Default::default()
+                }),
+                ..Default::default()
+            }],
+            ..Default::default()
+        }
+    }
+
+    /// Polls the vehicle at `secs` and returns its timestamp relative to `at(0)`, or `None`
+    /// if it was dropped, along with whether it was reported stale.
+    fn poll(
+        tracker: &mut ObservationTracker,
+        meters_east: f64,
+        secs: u64,
+        policy: StaleVehicles,
+    ) -> (Option<u64>, bool) {
+        let mut message = message(meters_east, None);
+        let stale = tracker.update(&mut message, at(secs), STALE_AFTER, policy);
+        let timestamp = message
+            .entity
+            .first()
+            .and_then(|entity| entity.vehicle.as_ref()?.timestamp)
+            .map(|t| t - 1_700_000_000);
+        (timestamp, stale.contains("bus"))
+    }
+
+    #[test]
+    fn timestamp_only_advances_when_the_vehicle_moves() {
+        let mut tracker = ObservationTracker::new();
+        let flag = StaleVehicles::Flag;
+
+        assert_eq!(poll(&mut tracker, 0.0, 0, flag).0, Some(0));
+        // A few meters of GPS jitter is the same position.
+        assert_eq!(poll(&mut tracker, 4.0, 30, flag).0, Some(0));
+        assert_eq!(poll(&mut tracker, 200.0, 60, flag).0, Some(60));
+        assert_eq!(poll(&mut tracker, 200.0, 90, flag).0, Some(60));
+    }
+
+    #[test]
+    fn slow_drift_is_measured_from_the_first_position() {
+        let mut tracker = ObservationTracker::new();
+        let flag = StaleVehicles::Flag;
+
+        poll(&mut tracker, 0.0, 0, flag);
+        assert_eq!(poll(&mut tracker, 6.0, 30, flag).0, Some(0));
+        assert_eq!(poll(&mut tracker, 12.0, 60, flag).0, Some(60));
+    }
+
+    #[test]
+    fn upstream_timestamp_is_kept() {
+        let mut tracker = ObservationTracker::new();
+        let upstream = 1_700_000_000 - 45;
+        let mut message = message(0.0, Some(upstream));
+
+        tracker.update(&mut message, at(0), STALE_AFTER, StaleVehicles::Drop);
+
+        let vehicle = message.entity[0].vehicle.as_ref().unwrap();
+        assert_eq!(vehicle.timestamp, Some(upstream));
+    }
+
+    #[test]
+    fn flagged_stale_vehicles_stay_in_the_feed() {
+        let mut tracker = ObservationTracker::new();
+        let flag = StaleVehicles::Flag;
+
+        poll(&mut tracker, 0.0, 0, flag);
+        assert_eq!(poll(&mut tracker, 0.0, 120, flag), (Some(0), false));
+        assert_eq!(poll(&mut tracker, 0.0, 150, flag), (Some(0), true));
+        assert_eq!(poll(&mut tracker, 200.0, 180, flag), (Some(180), false));
+    }
+
+    #[test]
+    fn dropped_stale_vehicles_return_once_they_move() {
+        let mut tracker = ObservationTracker::new();
+        let drop = StaleVehicles::Drop;
+
+        poll(&mut tracker, 0.0, 0, drop);
+        assert_eq!(poll(&mut tracker, 0.0, 150, drop), (None, true));
+        // Still remembered while missing from the output, so it stays stale.
+        assert_eq!(poll(&mut tracker, 0.0, 180, drop), (None, true));
+        assert_eq!(poll(&mut tracker, 200.0, 210, drop), (Some(210), false));
+    }
+}
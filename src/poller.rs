@@ -0,0 +1,353 @@
+use crate::agency::{self, FeedId};
+use crate::fetcher::{
+    FeedFetcher, combined_by_feed, fill_stop_status_by_feed, fill_trip_starts_by_feed,
+    trip_updates_by_feed, vehicle_positions_by_feed,
+};
+use crate::geojson::trips_to_geojson_by_feed;
+use crate::gtfs::FeedMessage;
+use crate::motion::MotionTracker;
+use crate::observation::{ObservationTracker, StaleVehicles};
+use crate::siri::{ServiceDelivery, estimated_timetable_by_feed, vehicle_monitoring_by_feed};
+use crate::static_gtfs::{self, StaticGtfs, ValidationReport};
+use std::collections::{BTreeSet, HashMap};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime};
+use tokio::sync::watch;
+use tokio::task::JoinHandle;
+
+/// Latest state of one feed as published by the [`Poller`].
+///
+/// Messages are kept from the last successful fetch, so a failed cycle leaves them untouched.
+#[derive(Debug, Clone, Default)]
+pub struct FeedSnapshot {
+    pub vehicle_positions: Option<Arc<FeedMessage>>,
+    pub trip_updates: Option<Arc<FeedMessage>>,
+    /// Vehicle positions with each vehicle's trip update attached.
+    pub combined: Option<Arc<FeedMessage>>,
+    /// End of the last cycle in which every fetch succeeded.
+    pub last_success: Option<SystemTime>,
+    pub last_error: Option<PollError>,
+    pub last_cycle: Option<CycleStats>,
+    /// Ids the static GTFS did not recognise in the latest messages, if one is configured.
+    pub validation: Option<ValidationReport>,
+    /// Vehicles that have not moved for longer than the agency's stale threshold.
+    pub stale_vehicles: BTreeSet<String>,
+    /// The same vehicles as SIRI Vehicle Monitoring.
+    pub siri_vm: Option<Arc<ServiceDelivery>>,
+    /// The same timetables as SIRI Estimated Timetable.
+    pub siri_et: Option<Arc<ServiceDelivery>>,
+    /// GeoJSON line per trip through its stops, see [`crate::geojson::trips_to_geojson`].
+    pub trip_paths: Option<Arc<serde_json::Value>>,
+}
+
+/// The messages a [`FeedSnapshot`] carries for each feed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum FeedKind {
+    VehiclePositions,
+    TripUpdates,
+    Combined,
+}
+
+impl FeedSnapshot {
+    /// Latest full dataset of `kind`.
+    pub fn message(&self, kind: FeedKind) -> Option<Arc<FeedMessage>> {
+        match kind {
+            FeedKind::VehiclePositions => self.vehicle_positions.clone(),
+            FeedKind::TripUpdates => self.trip_updates.clone(),
+            FeedKind::Combined => self.combined.clone(),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct PollError {
+    pub at: SystemTime,
+    pub message: String,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct CycleStats {
+    pub started_at: SystemTime,
+    pub vehicles_duration: Duration,
+    pub trip_updates_duration: Duration,
+    pub total_duration: Duration,
+}
+
+/// What one cycle produced for a feed; `None` fields leave the previous value in place.
+#[derive(Default)]
+struct FeedUpdate {
+    vehicle_positions: Option<FeedMessage>,
+    trip_updates: Option<FeedMessage>,
+    combined: Option<FeedMessage>,
+    validation: Option<ValidationReport>,
+    stale_vehicles: Option<BTreeSet<String>>,
+    siri_vm: Option<ServiceDelivery>,
+    siri_et: Option<ServiceDelivery>,
+    trip_paths: Option<serde_json::Value>,
+    error: Option<String>,
+}
+
+/// Keeps feeds warm by refreshing them on an interval and publishing each result
+/// through a `watch` channel per feed.
+#[derive(Clone)]
+pub struct Poller {
+    fetcher: FeedFetcher,
+    interval: Duration,
+    channels: Arc<HashMap<FeedId, watch::Sender<Arc<FeedSnapshot>>>>,
+    static_gtfs: Arc<HashMap<FeedId, StaticGtfs>>,
+    motion: Arc<Mutex<HashMap<FeedId, MotionTracker>>>,
+    observations: Arc<Mutex<HashMap<FeedId, ObservationTracker>>>,
+}
+
+impl Poller {
+    /// Static GTFS configured on the feeds' agencies is loaded here, once.
+    pub fn new(fetcher: FeedFetcher, feeds: Vec<FeedId>, interval: Duration) -> Self {
+        let static_gtfs = static_gtfs::load_for_feeds(&feeds);
+        let channels = feeds
+            .into_iter()
+            .map(|feed| {
+                let (tx, _) = watch::channel(Arc::new(FeedSnapshot::default()));
+                (feed, tx)
+            })
+            .collect();
+
+        Self {
+            fetcher,
+            interval,
+            channels: Arc::new(channels),
+            static_gtfs: Arc::new(static_gtfs),
+            motion: Arc::new(Mutex::new(HashMap::new())),
+            observations: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    pub fn fetcher(&self) -> &FeedFetcher {
+        &self.fetcher
+    }
+
+    pub fn feeds(&self) -> impl Iterator<Item = FeedId> + '_ {
+        self.channels.keys().copied()
+    }
+
+    /// Receives every snapshot published for `feed`, starting with the current one.
+    pub fn subscribe(&self, feed: FeedId) -> Option<watch::Receiver<Arc<FeedSnapshot>>> {
+        self.channels.get(&feed).map(|tx| tx.subscribe())
+    }
+
+    pub fn latest(&self, feed: FeedId) -> Option<Arc<FeedSnapshot>> {
+        self.channels.get(&feed).map(|tx| tx.borrow().clone())
+    }
+
+    /// Runs [`Poller::run`] on a background task.
+    pub fn spawn(&self) -> JoinHandle<()> {
+        let poller = self.clone();
+        tokio::spawn(async move { poller.run().await })
+    }
+
+    pub async fn run(&self) {
+        let mut ticker = tokio::time::interval(self.interval);
+        ticker.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+
+        loop {
+            ticker.tick().await;
+            self.poll_once().await;
+        }
+    }
+
+    /// Refreshes every feed once and publishes the results.
+    ///
+    /// The vehicle snapshot is downloaded once per tenant and shared by all feeds.
+    pub async fn poll_once(&self) {
+        let feeds: Vec<FeedId> = self.feeds().collect();
+        let started_at = SystemTime::now();
+        let start = Instant::now();
+
+        let snapshots = match self.fetcher.fetch_vehicle_snapshots(&feeds).await {
+            Ok(snapshots) => snapshots,
+            Err(e) => {
+                let message = format!("vehicle snapshot: {:#}", e);
+                eprintln!("Failed to refresh feeds: {}", message);
+                let stats = CycleStats {
+                    started_at,
+                    vehicles_duration: start.elapsed(),
+                    trip_updates_duration: Duration::ZERO,
+                    total_duration: start.elapsed(),
+                };
+                for &feed in &feeds {
+                    let update = FeedUpdate {
+                        error: Some(message.clone()),
+                        ..FeedUpdate::default()
+                    };
+                    self.publish(feed, update, stats);
+                }
+                return;
+            }
+        };
+        let mut vehicle_positions = vehicle_positions_by_feed(&snapshots);
+        let observed_at = SystemTime::now();
+        self.track_motion(&mut vehicle_positions, observed_at);
+        let mut stale_vehicles = self.track_observations(&mut vehicle_positions, observed_at);
+        let vehicles_duration = start.elapsed();
+
+        let trip_updates_start = Instant::now();
+        let timetables = self.fetcher.fetch_timetables_for(&snapshots).await;
+        let mut trip_updates = timetables.as_ref().map(trip_updates_by_feed);
+        let trip_updates_duration = trip_updates_start.elapsed();
+
+        let stats = CycleStats {
+            started_at,
+            vehicles_duration,
+            trip_updates_duration,
+            total_duration: start.elapsed(),
+        };
+
+        if let Ok(by_feed) = &timetables {
+            fill_stop_status_by_feed(&mut vehicle_positions, by_feed);
+        }
+        if let Ok(by_feed) = &trip_updates {
+            fill_trip_starts_by_feed(&mut vehicle_positions, by_feed);
+        }
+        let mut validation = self.enrich(&mut vehicle_positions, trip_updates.as_mut().ok());
+        let mut combined = match &trip_updates {
+            Ok(by_feed) => combined_by_feed(&vehicle_positions, by_feed),
+            Err(_) => HashMap::new(),
+        };
+        let mut siri_vm = vehicle_monitoring_by_feed(
+            &snapshots,
+            &vehicle_positions,
+            timetables.as_ref().ok(),
+            self.interval,
+        );
+        let (mut siri_et, mut trip_paths) = match &timetables {
+            Ok(by_feed) => (
+                estimated_timetable_by_feed(by_feed),
+                trips_to_geojson_by_feed(by_feed),
+            ),
+            Err(_) => (HashMap::new(), HashMap::new()),
+        };
+
+        for &feed in &feeds {
+            let (trips, error) = match &mut trip_updates {
+                Ok(by_feed) => (by_feed.remove(&feed), None),
+                Err(e) => (None, Some(format!("trip updates: {:#}", e))),
+            };
+            if let Some(message) = &error {
+                eprintln!("Failed to refresh {}: {}", feed, message);
+            }
+            let update = FeedUpdate {
+                vehicle_positions: vehicle_positions.remove(&feed),
+                trip_updates: trips,
+                combined: combined.remove(&feed),
+                validation: validation.remove(&feed),
+                stale_vehicles: stale_vehicles.remove(&feed),
+                siri_vm: siri_vm.remove(&feed),
+                siri_et: siri_et.remove(&feed),
+                trip_paths: trip_paths.remove(&feed),
+                error,
+            };
+            self.publish(feed, update, stats);
+        }
+    }
+
+    /// Fills speed and bearing from where each vehicle was on previous cycles.
+    fn track_motion(&self, vehicle_positions: &mut HashMap<FeedId, FeedMessage>, at: SystemTime) {
+        let mut trackers = self.motion.lock().unwrap();
+        for (&feed, message) in vehicle_positions.iter_mut() {
+            trackers.entry(feed).or_default().update(message, at);
+        }
+    }
+
+    /// Stamps each vehicle with when it last moved and applies the agency's stale policy.
+    fn track_observations(
+        &self,
+        vehicle_positions: &mut HashMap<FeedId, FeedMessage>,
+        at: SystemTime,
+    ) -> HashMap<FeedId, BTreeSet<String>> {
+        let mut trackers = self.observations.lock().unwrap();
+        vehicle_positions
+            .iter_mut()
+            .map(|(&feed, message)| {
+                let (stale_after, policy) = match agency::registry().get(feed) {
+                    Some(agency) => (agency.stale_after(), agency.stale_vehicles),
+                    None => (Duration::MAX, StaleVehicles::Flag),
+                };
+                let stale =
+                    trackers
+                        .entry(feed)
+                        .or_default()
+                        .update(message, at, stale_after, policy);
+                (feed, stale)
+            })
+            .collect()
+    }
+
+    /// Runs each feed's messages through its static GTFS, if one is loaded.
+    fn enrich(
+        &self,
+        vehicle_positions: &mut HashMap<FeedId, FeedMessage>,
+        mut trip_updates: Option<&mut HashMap<FeedId, FeedMessage>>,
+    ) -> HashMap<FeedId, ValidationReport> {
+        let mut reports = HashMap::new();
+        for (feed, gtfs) in self.static_gtfs.iter() {
+            let mut report = ValidationReport::default();
+            if let Some(message) = vehicle_positions.get_mut(feed) {
+                report.merge(gtfs.enrich(message));
+            }
+            if let Some(message) = trip_updates.as_mut().and_then(|t| t.get_mut(feed)) {
+                report.merge(gtfs.enrich(message));
+            }
+            if !report.is_clean() {
+                eprintln!(
+                    "{}: {} unknown trips, {} unknown routes, {} unknown stops",
+                    feed,
+                    report.unknown_trips.len(),
+                    report.unknown_routes.len(),
+                    report.unknown_stops.len()
+                );
+            }
+            reports.insert(*feed, report);
+        }
+        reports
+    }
+
+    fn publish(&self, feed: FeedId, update: FeedUpdate, stats: CycleStats) {
+        let Some(tx) = self.channels.get(&feed) else {
+            return;
+        };
+
+        let mut snapshot = FeedSnapshot::clone(&tx.borrow());
+        if let Some(message) = update.vehicle_positions {
+            snapshot.vehicle_positions = Some(Arc::new(message));
+        }
+        if let Some(message) = update.trip_updates {
+            snapshot.trip_updates = Some(Arc::new(message));
+        }
+        if let Some(message) = update.combined {
+            snapshot.combined = Some(Arc::new(message));
+        }
+        if update.validation.is_some() {
+            snapshot.validation = update.validation;
+        }
+        if let Some(stale) = update.stale_vehicles {
+            snapshot.stale_vehicles = stale;
+        }
+        if let Some(delivery) = update.siri_vm {
+            snapshot.siri_vm = Some(Arc::new(delivery));
+        }
+        if let Some(delivery) = update.siri_et {
+            snapshot.siri_et = Some(Arc::new(delivery));
+        }
+        if let Some(paths) = update.trip_paths {
+            snapshot.trip_paths = Some(Arc::new(paths));
+        }
+
+        let now = SystemTime::now();
+        match update.error {
+            Some(message) => snapshot.last_error = Some(PollError { at: now, message }),
+            None => snapshot.last_success = Some(now),
+        }
+        snapshot.last_cycle = Some(stats);
+
+        tx.send_replace(Arc::new(snapshot));
+    }
+}
@@ -0,0 +1,190 @@
+use crate::transport::HttpResponse;
+use anyhow::Result;
+use rand::Rng;
+use reqwest::StatusCode;
+use reqwest::header::RETRY_AFTER;
+use serde::{Deserialize, Serialize};
+use std::future::Future;
+use std::time::Duration;
+
+/// How Unwire requests are retried after transport errors and transient statuses.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct RetryPolicy {
+    /// Attempts per request including the first; `1` disables retries.
+    pub max_attempts: u32,
+    /// Delay before the first retry; doubled on every further retry.
+    pub base_delay_ms: u64,
+    /// Upper bound on any single delay, including one asked for by `Retry-After`.
+    pub max_delay_ms: u64,
+    /// Fraction of each backoff delay that is randomised, from `0.0` (none) to `1.0`.
+    pub jitter: f64,
+    pub retryable_statuses: Vec<u16>,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_attempts: 3,
+            base_delay_ms: 500,
+            max_delay_ms: 10_000,
+            jitter: 0.5,
+            retryable_statuses: vec![408, 429, 500, 502, 503, 504],
+        }
+    }
+}
+
+/// Lets [`RetryPolicy::send`] look at the HTTP response inside what an attempt returns.
+pub trait AttemptResponse {
+    fn response(&self) -> &HttpResponse;
+}
+
+impl AttemptResponse for HttpResponse {
+    fn response(&self) -> &HttpResponse {
+        self
+    }
+}
+
+impl<T> AttemptResponse for (HttpResponse, T) {
+    fn response(&self) -> &HttpResponse {
+        &self.0
+    }
+}
+
+impl RetryPolicy {
+    /// Runs `attempt` until it yields a response whose status is not retryable, or the
+    /// attempts run out. The last response or error is returned as is.
+    pub async fn send<T, F, Fut>(&self, what: &str, mut attempt: F) -> Result<T>
+    where
+        T: AttemptResponse,
+        F: FnMut() -> Fut,
+        Fut: Future<Output = Result<T>>,
+    {
+        let mut number = 1;
+        loop {
+            let result = attempt().await;
+            if number >= self.max_attempts {
+                return result;
+            }
+
+            let delay = match &result {
+                Ok(value) => {
+                    let response = value.response();
+                    if !self.is_retryable_status(response.status()) {
+                        return result;
+                    }
+                    eprintln!(
+                        "{} request returned {}; retrying ({}/{})",
+                        what,
+                        response.status(),
+                        number,
+                        self.max_attempts - 1
+                    );
+                    retry_after(response).unwrap_or_else(|| self.backoff(number))
+                }
+                Err(e) if is_transient(e) => {
+                    eprintln!(
+                        "{} request failed: {:#}; retrying ({}/{})",
+                        what,
+                        e,
+                        number,
+                        self.max_attempts - 1
+                    );
+                    self.backoff(number)
+                }
+                Err(_) => return result,
+            };
+
+            tokio::time::sleep(delay.min(self.max_delay())).await;
+            number += 1;
+        }
+    }
+
+    pub fn is_retryable_status(&self, status: StatusCode) -> bool {
+        self.retryable_statuses.contains(&status.as_u16())
+    }
+
+    /// Exponential delay before retry number `retry` (1-based), with jitter applied.
+    pub fn backoff(&self, retry: u32) -> Duration {
+        let exponential = self
+            .base_delay_ms
+            .saturating_mul(1u64 << retry.saturating_sub(1).min(20));
+        let capped = exponential.min(self.max_delay_ms) as f64;
+        let jitter = self.jitter.clamp(0.0, 1.0);
+        let factor = 1.0 - jitter * rand::thread_rng().r#gen::<f64>();
+        Duration::from_millis((capped * factor) as u64)
+    }
+
+    fn max_delay(&self) -> Duration {
+        Duration::from_millis(self.max_delay_ms)
+    }
+}
+
+/// Errors worth another try: the request never got a response.
+fn is_transient(error: &anyhow::Error) -> bool {
+    error
+        .downcast_ref::<reqwest::Error>()
+        .is_some_and(|e| e.is_timeout() || e.is_connect() || e.is_request())
+}
+
+/// `Retry-After` as either delay seconds or an HTTP date.
+fn retry_after(response: &HttpResponse) -> Option<Duration> {
+    let value = response.headers().get(RETRY_AFTER)?.to_str().ok()?.trim();
+    if let Ok(seconds) = value.parse::<u64>() {
+        return Some(Duration::from_secs(seconds));
+    }
+    let at = chrono::DateTime::parse_from_rfc2822(value).ok()?;
+    (at.with_timezone(&chrono::Utc) - chrono::Utc::now())
+        .to_std()
+        .ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn policy(jitter: f64) -> RetryPolicy {
+        RetryPolicy {
+            jitter,
+            ..RetryPolicy::default()
+        }
+    }
+
+    #[test]
+    fn backoff_doubles_up_to_the_cap() {
+        let policy = policy(0.0);
+        let delays: Vec<u64> = (1..=6)
+            .map(|retry| policy.backoff(retry).as_millis() as u64)
+            .collect();
+        assert_eq!(delays, [500, 1_000, 2_000, 4_000, 8_000, 10_000]);
+        assert_eq!(policy.backoff(u32::MAX), Duration::from_millis(10_000));
+    }
+
+    #[test]
+    fn jitter_only_shortens_the_delay() {
+        let policy = policy(0.5);
+        for _ in 0..100 {
+            let delay = policy.backoff(2);
+            assert!(delay >= Duration::from_millis(500));
+            assert!(delay <= Duration::from_millis(1_000));
+        }
+    }
+
+    #[test]
+    fn retries_only_configured_statuses() {
+        let policy = RetryPolicy::default();
+        assert!(policy.is_retryable_status(StatusCode::SERVICE_UNAVAILABLE));
+        assert!(policy.is_retryable_status(StatusCode::TOO_MANY_REQUESTS));
+        assert!(!policy.is_retryable_status(StatusCode::NOT_FOUND));
+        assert!(!policy.is_retryable_status(StatusCode::OK));
+    }
+
+    #[test]
+    fn reads_retry_after_seconds() {
+        let response =
+            HttpResponse::new(StatusCode::SERVICE_UNAVAILABLE, "").with_header("Retry-After", "7");
+        assert_eq!(retry_after(&response), Some(Duration::from_secs(7)));
+        let response = HttpResponse::new(StatusCode::SERVICE_UNAVAILABLE, "");
+        assert_eq!(retry_after(&response), None);
+    }
+}
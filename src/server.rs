@@ -0,0 +1,310 @@
+use crate::agency::FeedId;
+use crate::geojson::vehicles_to_geojson;
+use crate::poller::{FeedKind, FeedSnapshot, Poller};
+use crate::siri::ServiceDelivery;
+use crate::streaming::{StreamQuery, Subscription};
+use anyhow::{Context, Result};
+use axum::extract::ws::{Message as WsMessage, WebSocket, WebSocketUpgrade};
+use axum::extract::{Path, Query, State};
+use axum::http::{StatusCode, header};
+use axum::response::sse::{Event, KeepAlive, Sse};
+use axum::response::{IntoResponse, Response};
+use axum::routing::get;
+use axum::{Json, Router};
+use futures::stream;
+use prost::Message;
+use serde::Deserialize;
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+const PROTOBUF_CONTENT_TYPE: &str = "application/x-protobuf";
+const XML_CONTENT_TYPE: &str = "application/xml";
+const JSON_CONTENT_TYPE: &str = "application/json";
+const GEOJSON_CONTENT_TYPE: &str = "application/geo+json";
+
+#[derive(Clone)]
+struct AppState {
+    poller: Poller,
+}
+
+#[derive(Debug, Deserialize)]
+struct FormatQuery {
+    format: Option<String>,
+}
+
+/// Serves the feeds kept warm by `poller` on `addr`; requests never reach Unwire directly.
+///
+/// Routes are `/{feed}/vehicle_positions.pb`, `/{feed}/trip_updates.pb` and
+/// `/{feed}/combined.pb`; add `?format=json` for a JSON rendering of the same message. They
+/// always serve full datasets: a client polling over HTTP can miss updates, so differential
+/// messages are only offered on the streams, which diff against what each client was sent.
+///
+/// `/{feed}/vehicles.geojson` and `/{feed}/trips.geojson` render vehicles and trip paths
+/// as GeoJSON.
+///
+/// `/{feed}/siri/vm` and `/{feed}/siri/et` serve the same data as SIRI XML, or SIRI-Lite
+/// with `?format=json`.
+///
+/// `/circuits` reports the state of every gateway circuit breaker as JSON.
+///
+/// `/{feed}/stream` (WebSocket) and `/{feed}/events` (SSE) push each new message as it is
+/// published; see [`StreamQuery`] for their filters.
+pub async fn serve(addr: SocketAddr, poller: Poller) -> Result<()> {
+    let listener = tokio::net::TcpListener::bind(addr)
+        .await
+        .with_context(|| format!("failed to bind {}", addr))?;
+    println!("Serving GTFS-RT on http://{}", addr);
+
+    axum::serve(listener, router(poller))
+        .await
+        .context("server error")
+}
+
+pub fn router(poller: Poller) -> Router {
+    Router::new()
+        .route("/circuits", get(circuits))
+        .route("/{feed}/vehicle_positions.pb", get(vehicle_positions))
+        .route("/{feed}/trip_updates.pb", get(trip_updates))
+        .route("/{feed}/combined.pb", get(combined))
+        .route("/{feed}/vehicles.geojson", get(vehicles_geojson))
+        .route("/{feed}/trips.geojson", get(trips_geojson))
+        .route("/{feed}/siri/vm", get(siri_vm))
+        .route("/{feed}/siri/et", get(siri_et))
+        .route("/{feed}/stream", get(stream_websocket))
+        .route("/{feed}/events", get(stream_events))
+        .with_state(AppState { poller })
+}
+
+async fn circuits(State(state): State<AppState>) -> Response {
+    Json(state.poller.fetcher().circuit_statuses().await).into_response()
+}
+
+async fn vehicle_positions(
+    State(state): State<AppState>,
+    Path(feed): Path<String>,
+    Query(query): Query<FormatQuery>,
+) -> Response {
+    respond(&state, &feed, &query, FeedKind::VehiclePositions).await
+}
+
+async fn trip_updates(
+    State(state): State<AppState>,
+    Path(feed): Path<String>,
+    Query(query): Query<FormatQuery>,
+) -> Response {
+    respond(&state, &feed, &query, FeedKind::TripUpdates).await
+}
+
+async fn combined(
+    State(state): State<AppState>,
+    Path(feed): Path<String>,
+    Query(query): Query<FormatQuery>,
+) -> Response {
+    respond(&state, &feed, &query, FeedKind::Combined).await
+}
+
+async fn respond(state: &AppState, feed: &str, query: &FormatQuery, kind: FeedKind) -> Response {
+    let Some(feed) = find_feed(state, feed) else {
+        return (StatusCode::NOT_FOUND, "unknown feed").into_response();
+    };
+
+    let message = state
+        .poller
+        .latest(feed)
+        .and_then(|snapshot| snapshot.message(kind));
+    let Some(message) = message else {
+        return (StatusCode::SERVICE_UNAVAILABLE, "feed not available yet").into_response();
+    };
+
+    match query.format.as_deref() {
+        Some("json") => Json(message.as_ref()).into_response(),
+        Some("pb") | None => (
+            [(header::CONTENT_TYPE, PROTOBUF_CONTENT_TYPE)],
+            message.encode_to_vec(),
+        )
+            .into_response(),
+        Some(other) => (
+            StatusCode::BAD_REQUEST,
+            format!("unsupported format {}", other),
+        )
+            .into_response(),
+    }
+}
+
+async fn vehicles_geojson(State(state): State<AppState>, Path(feed): Path<String>) -> Response {
+    respond_geojson(&state, &feed, |snapshot| {
+        let vehicles = snapshot.vehicle_positions.as_ref()?;
+        Some(Arc::new(vehicles_to_geojson(
+            vehicles,
+            &snapshot.stale_vehicles,
+        )))
+    })
+}
+
+async fn trips_geojson(State(state): State<AppState>, Path(feed): Path<String>) -> Response {
+    respond_geojson(&state, &feed, |snapshot| snapshot.trip_paths.clone())
+}
+
+fn respond_geojson(
+    state: &AppState,
+    feed: &str,
+    select: impl Fn(&FeedSnapshot) -> Option<Arc<serde_json::Value>>,
+) -> Response {
+    let Some(feed) = find_feed(state, feed) else {
+        return (StatusCode::NOT_FOUND, "unknown feed").into_response();
+    };
+    let Some(collection) = state.poller.latest(feed).and_then(|s| select(&s)) else {
+        return (StatusCode::SERVICE_UNAVAILABLE, "feed not available yet").into_response();
+    };
+    (
+        [(header::CONTENT_TYPE, GEOJSON_CONTENT_TYPE)],
+        collection.to_string(),
+    )
+        .into_response()
+}
+
+async fn siri_vm(
+    State(state): State<AppState>,
+    Path(feed): Path<String>,
+    Query(query): Query<FormatQuery>,
+) -> Response {
+    respond_siri(&state, &feed, &query, |snapshot| snapshot.siri_vm.clone())
+}
+
+async fn siri_et(
+    State(state): State<AppState>,
+    Path(feed): Path<String>,
+    Query(query): Query<FormatQuery>,
+) -> Response {
+    respond_siri(&state, &feed, &query, |snapshot| snapshot.siri_et.clone())
+}
+
+fn respond_siri(
+    state: &AppState,
+    feed: &str,
+    query: &FormatQuery,
+    select: impl Fn(&FeedSnapshot) -> Option<Arc<ServiceDelivery>>,
+) -> Response {
+    let Some(feed) = find_feed(state, feed) else {
+        return (StatusCode::NOT_FOUND, "unknown feed").into_response();
+    };
+    let Some(delivery) = state.poller.latest(feed).and_then(|s| select(&s)) else {
+        return (StatusCode::SERVICE_UNAVAILABLE, "feed not available yet").into_response();
+    };
+
+    let (content_type, body) = match query.format.as_deref() {
+        Some("xml") | None => (XML_CONTENT_TYPE, delivery.to_xml()),
+        Some("json") => (JSON_CONTENT_TYPE, delivery.to_lite_json()),
+        Some(other) => {
+            return (
+                StatusCode::BAD_REQUEST,
+                format!("unsupported format {}", other),
+            )
+                .into_response();
+        }
+    };
+    match body {
+        Ok(body) => ([(header::CONTENT_TYPE, content_type)], body).into_response(),
+        Err(e) => (StatusCode::INTERNAL_SERVER_ERROR, format!("{:#}", e)).into_response(),
+    }
+}
+
+fn find_feed(state: &AppState, feed: &str) -> Option<FeedId> {
+    state
+        .poller
+        .feeds()
+        .find(|f| f.as_str().eq_ignore_ascii_case(feed))
+}
+
+fn subscribe(
+    state: &AppState,
+    feed: &str,
+    query: &StreamQuery,
+) -> std::result::Result<Subscription, (StatusCode, String)> {
+    let unknown = || (StatusCode::NOT_FOUND, "unknown feed".to_string());
+    let feed = find_feed(state, feed).ok_or_else(unknown)?;
+    match Subscription::new(&state.poller, feed, query) {
+        Ok(Some(subscription)) => Ok(subscription),
+        Ok(None) => Err(unknown()),
+        Err(e) => Err((StatusCode::BAD_REQUEST, e.to_string())),
+    }
+}
+
+async fn stream_websocket(
+    State(state): State<AppState>,
+    Path(feed): Path<String>,
+    Query(query): Query<StreamQuery>,
+    upgrade: WebSocketUpgrade,
+) -> Response {
+    let json = match query.format.as_deref() {
+        Some("pb") | None => false,
+        Some("json") => true,
+        Some(other) => {
+            return (
+                StatusCode::BAD_REQUEST,
+                format!("unsupported format {}", other),
+            )
+                .into_response();
+        }
+    };
+    let subscription = match subscribe(&state, &feed, &query) {
+        Ok(subscription) => subscription,
+        Err(rejection) => return rejection.into_response(),
+    };
+    upgrade.on_upgrade(move |socket| push_websocket(socket, subscription, json))
+}
+
+/// Sends each message as a binary protobuf frame, or a JSON text frame, until either side
+/// goes away.
+async fn push_websocket(mut socket: WebSocket, mut subscription: Subscription, json: bool) {
+    loop {
+        tokio::select! {
+            message = subscription.next() => {
+                let Some(message) = message else {
+                    break;
+                };
+                let frame = if json {
+                    match serde_json::to_string(&message) {
+                        Ok(text) => WsMessage::Text(text.into()),
+                        Err(e) => {
+                            eprintln!("Failed to encode stream message: {}", e);
+                            break;
+                        }
+                    }
+                } else {
+                    WsMessage::Binary(message.encode_to_vec().into())
+                };
+                if socket.send(frame).await.is_err() {
+                    break;
+                }
+            }
+            incoming = socket.recv() => {
+                if matches!(incoming, None | Some(Err(_)) | Some(Ok(WsMessage::Close(_)))) {
+                    break;
+                }
+            }
+        }
+    }
+}
+
+/// Sends each message as a JSON `data` payload; the event name is the message kind.
+async fn stream_events(
+    State(state): State<AppState>,
+    Path(feed): Path<String>,
+    Query(query): Query<StreamQuery>,
+) -> Response {
+    let subscription = match subscribe(&state, &feed, &query) {
+        Ok(subscription) => subscription,
+        Err(rejection) => return rejection.into_response(),
+    };
+    let events = stream::unfold(subscription, |mut subscription| async move {
+        let message = subscription.next().await?;
+        let event = Event::default()
+            .event(subscription.kind_name())
+            .json_data(&message);
+        Some((event, subscription))
+    });
+    Sse::new(events)
+        .keep_alive(KeepAlive::default())
+        .into_response()
+}
@@ -0,0 +1,608 @@
+use crate::agency::FeedId;
+use crate::fetcher::TripTimetable;
+use crate::gtfs::{ConversionOptions, FeedMessage, service_start, trip_start};
+use crate::model::{TimeInfo, TimeState, TripState, TripUpdateResponse, VehicleContent};
+use crate::strip_prefix;
+use anyhow::{Context, Result};
+use chrono::{DateTime, SecondsFormat, Utc};
+use chrono_tz::Tz;
+use serde::Serialize;
+use std::collections::{HashMap, HashSet};
+use std::time::Duration;
+
+const SIRI_VERSION: &str = "2.0";
+const SIRI_NAMESPACE: &str = "http://www.siri.org.uk/siri";
+
+/// A SIRI `ServiceDelivery` holding either vehicle monitoring or estimated timetable data.
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct ServiceDelivery {
+    pub response_timestamp: String,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub vehicle_monitoring_delivery: Vec<VehicleMonitoringDelivery>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub estimated_timetable_delivery: Vec<EstimatedTimetableDelivery>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct VehicleMonitoringDelivery {
+    pub response_timestamp: String,
+    pub vehicle_activity: Vec<VehicleActivity>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct VehicleActivity {
+    pub recorded_at_time: String,
+    /// When consumers should stop showing this activity: the next poll is due by then.
+    pub valid_until_time: String,
+    pub monitored_vehicle_journey: MonitoredVehicleJourney,
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct MonitoredVehicleJourney {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub line_ref: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub direction_ref: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub framed_vehicle_journey_ref: Option<FramedVehicleJourneyRef>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub published_line_name: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub destination_name: Option<String>,
+    pub monitored: bool,
+    pub vehicle_location: VehicleLocation,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub bearing: Option<f64>,
+    pub vehicle_ref: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub monitored_call: Option<MonitoredCall>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct FramedVehicleJourneyRef {
+    /// Service date, `YYYY-MM-DD`.
+    pub data_frame_ref: String,
+    pub dated_vehicle_journey_ref: String,
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct VehicleLocation {
+    pub longitude: f64,
+    pub latitude: f64,
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct MonitoredCall {
+    pub stop_point_ref: String,
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct EstimatedTimetableDelivery {
+    pub response_timestamp: String,
+    pub estimated_journey_version_frame: Vec<EstimatedJourneyVersionFrame>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct EstimatedJourneyVersionFrame {
+    pub recorded_at_time: String,
+    pub estimated_vehicle_journey: Vec<EstimatedVehicleJourney>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct EstimatedVehicleJourney {
+    pub recorded_at_time: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub line_ref: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub direction_ref: Option<String>,
+    pub framed_vehicle_journey_ref: FramedVehicleJourneyRef,
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub extra_journey: bool,
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub cancellation: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub vehicle_ref: Option<String>,
+    pub estimated_calls: EstimatedCalls,
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct EstimatedCalls {
+    pub estimated_call: Vec<EstimatedCall>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct EstimatedCall {
+    pub stop_point_ref: String,
+    pub order: u32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub stop_point_name: Option<String>,
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub cancellation: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub aimed_arrival_time: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub expected_arrival_time: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub aimed_departure_time: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub expected_departure_time: Option<String>,
+}
+
+impl ServiceDelivery {
+    /// SIRI XML document with a `<Siri>` root.
+    pub fn to_xml(&self) -> Result<String> {
+        #[derive(Serialize)]
+        struct Root<'a> {
+            #[serde(rename = "@version")]
+            version: &'a str,
+            #[serde(rename = "@xmlns")]
+            xmlns: &'a str,
+            #[serde(rename = "ServiceDelivery")]
+            service_delivery: &'a ServiceDelivery,
+        }
+
+        let body = quick_xml::se::to_string_with_root(
+            "Siri",
+            &Root {
+                version: SIRI_VERSION,
+                xmlns: SIRI_NAMESPACE,
+                service_delivery: self,
+            },
+        )
+        .context("failed to serialize SIRI XML")?;
+        Ok(format!(
+            "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n{}",
+            body
+        ))
+    }
+
+    /// SIRI-Lite JSON, `{"Siri": {"ServiceDelivery": ...}}`.
+    pub fn to_lite_json(&self) -> Result<String> {
+        #[derive(Serialize)]
+        struct Siri<'a> {
+            #[serde(rename = "ServiceDelivery")]
+            service_delivery: &'a ServiceDelivery,
+        }
+        #[derive(Serialize)]
+        struct Root<'a> {
+            #[serde(rename = "Siri")]
+            siri: Siri<'a>,
+        }
+
+        serde_json::to_string(&Root {
+            siri: Siri {
+                service_delivery: self,
+            },
+        })
+        .context("failed to serialize SIRI-Lite JSON")
+    }
+}
+
+/// SIRI-VM delivery with one `VehicleActivity` per vehicle, each valid for `valid_for`.
+///
+/// A vehicle's journey is framed on the service date of its trip in `timetables`, or on
+/// today's service date if its trip was not fetched.
+pub fn vehicle_monitoring(
+    vehicles: &[VehicleContent],
+    timetables: &[TripTimetable],
+    options: &ConversionOptions,
+    valid_for: Duration,
+) -> ServiceDelivery {
+    let now = Utc::now();
+    let service_dates: HashMap<&str, String> = timetables
+        .iter()
+        .filter_map(|t| {
+            let (date, _) = trip_start(&t.response.entries, options)?;
+            Some((t.trip_id.as_str(), date))
+        })
+        .collect();
+    let activities = vehicles
+        .iter()
+        .map(|v| {
+            let service_date = v.trip.as_ref().and_then(|trip| {
+                service_dates.get(format!("{}:{}", trip.feed_id, trip.id).as_str())
+            });
+            vehicle_activity(v, service_date.map(String::as_str), options, now, valid_for)
+        })
+        .collect();
+    let now = format_time(now);
+
+    ServiceDelivery {
+        response_timestamp: now.clone(),
+        vehicle_monitoring_delivery: vec![VehicleMonitoringDelivery {
+            response_timestamp: now,
+            vehicle_activity: activities,
+        }],
+        estimated_timetable_delivery: Vec::new(),
+    }
+}
+
+/// SIRI-ET delivery with one `EstimatedVehicleJourney` per timetable.
+pub fn estimated_timetable(
+    timetables: &[TripTimetable],
+    options: &ConversionOptions,
+) -> ServiceDelivery {
+    let now = timestamp();
+    let journeys = timetables
+        .iter()
+        .map(|t| estimated_vehicle_journey(&t.trip_id, &t.response, Some(&t.vehicle), options))
+        .collect();
+
+    ServiceDelivery {
+        response_timestamp: now.clone(),
+        vehicle_monitoring_delivery: Vec::new(),
+        estimated_timetable_delivery: vec![EstimatedTimetableDelivery {
+            response_timestamp: now.clone(),
+            estimated_journey_version_frame: vec![EstimatedJourneyVersionFrame {
+                recorded_at_time: now,
+                estimated_vehicle_journey: journeys,
+            }],
+        }],
+    }
+}
+
+/// [`vehicle_monitoring`] for every feed, limited to the vehicles still published in
+/// `vehicle_positions` so stale vehicles dropped there are dropped here too.
+pub fn vehicle_monitoring_by_feed(
+    snapshots: &HashMap<FeedId, Vec<VehicleContent>>,
+    vehicle_positions: &HashMap<FeedId, FeedMessage>,
+    timetables: Option<&HashMap<FeedId, Vec<TripTimetable>>>,
+    valid_for: Duration,
+) -> HashMap<FeedId, ServiceDelivery> {
+    snapshots
+        .iter()
+        .map(|(&feed, vehicles)| {
+            let published: HashSet<&str> = vehicle_positions
+                .get(&feed)
+                .into_iter()
+                .flat_map(|message| &message.entity)
+                .filter_map(|entity| entity.vehicle.as_ref()?.vehicle.as_ref()?.id.as_deref())
+                .collect();
+            let vehicles: Vec<VehicleContent> = vehicles
+                .iter()
+                .filter(|v| published.contains(strip_prefix(&v.id).as_str()))
+                .cloned()
+                .collect();
+            let trips = timetables
+                .and_then(|by_feed| by_feed.get(&feed))
+                .map_or(&[][..], Vec::as_slice);
+            let delivery = vehicle_monitoring(
+                &vehicles,
+                trips,
+                &ConversionOptions::for_feed(feed),
+                valid_for,
+            );
+            (feed, delivery)
+        })
+        .collect()
+}
+
+/// [`estimated_timetable`] for every feed.
+pub fn estimated_timetable_by_feed(
+    timetables: &HashMap<FeedId, Vec<TripTimetable>>,
+) -> HashMap<FeedId, ServiceDelivery> {
+    timetables
+        .iter()
+        .map(|(&feed, trips)| {
+            let delivery = estimated_timetable(trips, &ConversionOptions::for_feed(feed));
+            (feed, delivery)
+        })
+        .collect()
+}
+
+/// `service_date` (`YYYYMMDD`) is the trip's start date if known; otherwise the journey is
+/// framed on today's service day, since the snapshot does not say when a trip started.
+pub fn vehicle_activity(
+    v: &VehicleContent,
+    service_date: Option<&str>,
+    options: &ConversionOptions,
+    polled_at: DateTime<Utc>,
+    valid_for: Duration,
+) -> VehicleActivity {
+    let recorded_at = v
+        .timestamp
+        .as_ref()
+        .and_then(|t| t.unix_seconds())
+        .and_then(|t| DateTime::from_timestamp(t as i64, 0))
+        .unwrap_or(polled_at);
+    let valid_until = polled_at.max(recorded_at)
+        + chrono::Duration::from_std(valid_for).unwrap_or_else(|_| chrono::Duration::zero());
+
+    let service_date = match service_date {
+        Some(date) => date.to_string(),
+        None => current_service_date(options),
+    };
+
+    VehicleActivity {
+        recorded_at_time: format_time(recorded_at),
+        valid_until_time: format_time(valid_until),
+        monitored_vehicle_journey: MonitoredVehicleJourney {
+            line_ref: v.route.as_ref().map(|r| strip_prefix(&r.id)),
+            direction_ref: v.direction_id.map(|d| d.to_string()),
+            framed_vehicle_journey_ref: v.trip.as_ref().map(|trip| FramedVehicleJourneyRef {
+                data_frame_ref: data_frame_ref(&service_date),
+                dated_vehicle_journey_ref: strip_prefix(&trip.id),
+            }),
+            published_line_name: v.short_code.clone(),
+            destination_name: v.head_sign.clone(),
+            monitored: true,
+            vehicle_location: VehicleLocation {
+                longitude: v.coordinate.lng,
+                latitude: v.coordinate.lat,
+            },
+            bearing: v.orientation,
+            vehicle_ref: strip_prefix(&v.id),
+            monitored_call: v.stop.as_ref().map(|s| MonitoredCall {
+                stop_point_ref: strip_prefix(&s.id),
+            }),
+        },
+    }
+}
+
+/// SIRI-ET journey for one trip; `trip_id` is the full Unwire id, e.g. `DART:1234`.
+pub fn estimated_vehicle_journey(
+    trip_id: &str,
+    update: &TripUpdateResponse,
+    vehicle: Option<&VehicleContent>,
+    options: &ConversionOptions,
+) -> EstimatedVehicleJourney {
+    let service_date = match trip_start(&update.entries, options) {
+        Some((date, _)) => date,
+        None => current_service_date(options),
+    };
+
+    let mut entries: Vec<_> = update.entries.iter().collect();
+    entries.sort_by_key(|entry| entry.stop.index);
+    let calls = entries
+        .into_iter()
+        .map(|entry| {
+            let cancellation = [&entry.arrival, &entry.departure]
+                .into_iter()
+                .flatten()
+                .any(|info| info.state == Some(TimeState::Skipped));
+            let (aimed_arrival_time, expected_arrival_time) = aimed_and_expected(&entry.arrival);
+            let (aimed_departure_time, expected_departure_time) =
+                aimed_and_expected(&entry.departure);
+            EstimatedCall {
+                stop_point_ref: strip_prefix(&entry.stop.id),
+                order: entry.stop.index,
+                stop_point_name: entry.stop.name.clone(),
+                cancellation,
+                aimed_arrival_time,
+                expected_arrival_time,
+                aimed_departure_time,
+                expected_departure_time,
+            }
+        })
+        .collect();
+
+    EstimatedVehicleJourney {
+        recorded_at_time: timestamp(),
+        line_ref: vehicle
+            .and_then(|v| v.route.as_ref())
+            .map(|r| strip_prefix(&r.id)),
+        direction_ref: vehicle.and_then(|v| v.direction_id).map(|d| d.to_string()),
+        framed_vehicle_journey_ref: FramedVehicleJourneyRef {
+            data_frame_ref: data_frame_ref(&service_date),
+            dated_vehicle_journey_ref: strip_prefix(trip_id),
+        },
+        extra_journey: update.state == Some(TripState::Added),
+        cancellation: update.state == Some(TripState::Cancelled),
+        vehicle_ref: vehicle.map(|v| strip_prefix(&v.id)),
+        estimated_calls: EstimatedCalls {
+            estimated_call: calls,
+        },
+    }
+}
+
+/// Aimed time from the schedule, and an expected time only when Unwire has a real prediction.
+fn aimed_and_expected(info: &Option<TimeInfo>) -> (Option<String>, Option<String>) {
+    let Some(info) = info else {
+        return (None, None);
+    };
+    let expected = match info.state {
+        Some(TimeState::Scheduled | TimeState::Skipped) => None,
+        _ => info.real.clone(),
+    };
+    (info.scheduled.clone(), expected)
+}
+
+/// Today's service date, `YYYYMMDD`. `DataFrameRef` is mandatory, so feeds without a known
+/// timezone fall back to UTC here.
+fn current_service_date(options: &ConversionOptions) -> String {
+    let timezone = options.timezone.unwrap_or(Tz::UTC);
+    let local = Utc::now().with_timezone(&timezone).naive_local();
+    service_start(local, options.service_day_cutoff_hour).0
+}
+
+/// `YYYYMMDD` as the `YYYY-MM-DD` SIRI expects.
+fn data_frame_ref(service_date: &str) -> String {
+    match (
+        service_date.get(0..4),
+        service_date.get(4..6),
+        service_date.get(6..8),
+    ) {
+        (Some(year), Some(month), Some(day)) => format!("{}-{}-{}", year, month, day),
+        _ => service_date.to_string(),
+    }
+}
+
+fn timestamp() -> String {
+    format_time(Utc::now())
+}
+
+fn format_time(time: DateTime<Utc>) -> String {
+    time.to_rfc3339_opts(SecondsFormat::Secs, true)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Departs its first stop at 01:10 Chicago time on March 2nd, so it runs on the service
+    /// day of March 1st.
+    const TIMETABLE: &str = r#"{"state":"REALTIME","entries":[
+        {"stop":{"id":"DART:S2","name":"Second","index":2},
+         "arrival":{"state":"PREDICTED","scheduled":"2024-03-02T07:30:00Z","real":"2024-03-02T07:32:00Z"}},
+        {"stop":{"id":"DART:S1","name":"First","index":1},
+         "departure":{"state":"SCHEDULED","scheduled":"2024-03-02T07:10:00Z"}}
+    ]}"#;
+
+    const VEHICLE: &str = r#"{
+        "id":"DART-42",
+        "coordinate":{"lat":33.2,"lng":-97.1},
+        "orientation":90.0,
+        "trip":{"id":"1234","feedId":"DART"},
+        "route":{"id":"DART:A","feedId":"DART"},
+        "stop":{"id":"DART:S1","feedId":"DART"},
+        "directionId":1
+    }"#;
+
+    fn timetable() -> TripTimetable {
+        TripTimetable {
+            trip_id: "DART:1234".to_string(),
+            vehicle: serde_json::from_str(VEHICLE).unwrap(),
+            response: serde_json::from_str(TIMETABLE).unwrap(),
+        }
+    }
+
+    fn chicago() -> ConversionOptions {
+        ConversionOptions {
+            timezone: Some(chrono_tz::America::Chicago),
+            ..ConversionOptions::default()
+        }
+    }
+
+    /// Asserts that each element in `tags` opens after the one before it.
+    fn assert_in_order(xml: &str, tags: &[&str]) {
+        let mut last = 0;
+        for tag in tags {
+            let at = xml[last..]
+                .find(&format!("<{}>", tag))
+                .unwrap_or_else(|| panic!("<{}> missing or out of order in {}", tag, xml));
+            last += at;
+        }
+    }
+
+    #[test]
+    fn vehicle_monitoring_xml_follows_the_schema_order() {
+        let timetable = timetable();
+        let delivery = vehicle_monitoring(
+            std::slice::from_ref(&timetable.vehicle),
+            std::slice::from_ref(&timetable),
+            &chicago(),
+            Duration::from_secs(30),
+        );
+
+        let xml = delivery.to_xml().unwrap();
+
+        assert_in_order(
+            &xml,
+            &[
+                "ServiceDelivery",
+                "ResponseTimestamp",
+                "VehicleMonitoringDelivery",
+                "ResponseTimestamp",
+                "VehicleActivity",
+                "RecordedAtTime",
+                "ValidUntilTime",
+                "MonitoredVehicleJourney",
+                "LineRef",
+                "DirectionRef",
+                "FramedVehicleJourneyRef",
+                "DataFrameRef",
+                "DatedVehicleJourneyRef",
+                "Monitored",
+                "VehicleLocation",
+                "Longitude",
+                "Latitude",
+                "Bearing",
+                "VehicleRef",
+                "MonitoredCall",
+                "StopPointRef",
+            ],
+        );
+        assert!(
+            xml.contains("<DataFrameRef>2024-03-01</DataFrameRef>"),
+            "{xml}"
+        );
+        assert!(xml.contains("<DatedVehicleJourneyRef>1234</DatedVehicleJourneyRef>"));
+        assert!(xml.contains("<VehicleRef>42</VehicleRef>"));
+    }
+
+    #[test]
+    fn vehicle_activity_is_valid_until_the_next_poll() {
+        let polled_at = "2024-03-02T07:20:00Z".parse().unwrap();
+
+        let activity = vehicle_activity(
+            &timetable().vehicle,
+            Some("20240301"),
+            &chicago(),
+            polled_at,
+            Duration::from_secs(30),
+        );
+
+        assert_eq!(activity.recorded_at_time, "2024-03-02T07:20:00Z");
+        assert_eq!(activity.valid_until_time, "2024-03-02T07:20:30Z");
+        let framed = activity
+            .monitored_vehicle_journey
+            .framed_vehicle_journey_ref;
+        assert_eq!(framed.unwrap().data_frame_ref, "2024-03-01");
+    }
+
+    #[test]
+    fn estimated_timetable_xml_follows_the_schema_order() {
+        let delivery = estimated_timetable(&[timetable()], &chicago());
+
+        let xml = delivery.to_xml().unwrap();
+
+        assert_in_order(
+            &xml,
+            &[
+                "EstimatedTimetableDelivery",
+                "EstimatedJourneyVersionFrame",
+                "RecordedAtTime",
+                "EstimatedVehicleJourney",
+                "RecordedAtTime",
+                "LineRef",
+                "DirectionRef",
+                "FramedVehicleJourneyRef",
+                "DataFrameRef",
+                "DatedVehicleJourneyRef",
+                "VehicleRef",
+                "EstimatedCalls",
+                // Calls come in stop order, not in the order Unwire listed them.
+                "EstimatedCall",
+                "StopPointRef",
+                "Order",
+                "StopPointName",
+                "AimedDepartureTime",
+                "EstimatedCall",
+                "StopPointRef",
+                "Order",
+                "StopPointName",
+                "AimedArrivalTime",
+                "ExpectedArrivalTime",
+            ],
+        );
+        assert!(
+            xml.contains("<DataFrameRef>2024-03-01</DataFrameRef>"),
+            "{xml}"
+        );
+        assert!(xml.contains("<StopPointRef>S1</StopPointRef><Order>1</Order>"));
+        assert!(!xml.contains("ExpectedDepartureTime"));
+        assert!(!xml.contains("Cancellation") && !xml.contains("ExtraJourney"));
+    }
+}
@@ -0,0 +1,481 @@
+use crate::agency::{self, FeedId};
+use crate::gtfs::{FeedMessage, TripDescriptor};
+use anyhow::{Context, Result};
+use serde::Deserialize;
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::fs::File;
+use std::io::{Read, Seek};
+use std::path::Path;
+use zip::ZipArchive;
+
+#[derive(Debug, Deserialize)]
+struct TripRow {
+    trip_id: String,
+    route_id: String,
+    #[serde(default)]
+    direction_id: Option<u32>,
+}
+
+#[derive(Debug, Deserialize)]
+struct RouteRow {
+    route_id: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct StopRow {
+    stop_id: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct StopTimeRow {
+    trip_id: String,
+    stop_id: String,
+    stop_sequence: u32,
+    #[serde(default)]
+    departure_time: Option<String>,
+}
+
+#[derive(Debug, Clone)]
+struct StaticTrip {
+    route_id: String,
+    direction_id: Option<u32>,
+}
+
+#[derive(Debug, Clone)]
+struct StaticStopTime {
+    stop_id: String,
+    stop_sequence: u32,
+    departure_time: Option<String>,
+}
+
+/// The parts of an agency's static GTFS needed to validate and fill in realtime ids.
+#[derive(Debug, Default)]
+pub struct StaticGtfs {
+    trips: HashMap<String, StaticTrip>,
+    routes: HashSet<String>,
+    stops: HashSet<String>,
+    /// Stop times per trip, ordered by `stop_sequence`.
+    stop_times: HashMap<String, Vec<StaticStopTime>>,
+}
+
+/// Ids in a realtime feed that the static GTFS does not know about, plus what was corrected.
+#[derive(Debug, Clone, Default)]
+pub struct ValidationReport {
+    pub unknown_trips: BTreeSet<String>,
+    pub unknown_routes: BTreeSet<String>,
+    pub unknown_stops: BTreeSet<String>,
+    /// Stop time updates and vehicles whose stop sequence was replaced by the static value.
+    pub corrected_stop_sequences: usize,
+    /// Trip descriptors that gained a `route_id`, `direction_id` or `start_time`.
+    pub filled_trips: usize,
+}
+
+impl ValidationReport {
+    pub fn is_clean(&self) -> bool {
+        self.unknown_trips.is_empty()
+            && self.unknown_routes.is_empty()
+            && self.unknown_stops.is_empty()
+    }
+
+    pub fn merge(&mut self, other: ValidationReport) {
+        self.unknown_trips.extend(other.unknown_trips);
+        self.unknown_routes.extend(other.unknown_routes);
+        self.unknown_stops.extend(other.unknown_stops);
+        self.corrected_stop_sequences += other.corrected_stop_sequences;
+        self.filled_trips += other.filled_trips;
+    }
+}
+
+impl StaticGtfs {
+    /// Loads `trips.txt`, `routes.txt`, `stops.txt` and `stop_times.txt` from a GTFS zip.
+    pub fn from_zip(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        let file =
+            File::open(path).with_context(|| format!("failed to open {}", path.display()))?;
+        Self::from_reader(file).with_context(|| format!("failed to load {}", path.display()))
+    }
+
+    /// Like [`StaticGtfs::from_zip`], reading the zip from `reader`.
+    pub fn from_reader(reader: impl Read + Seek) -> Result<Self> {
+        let mut archive = ZipArchive::new(reader).context("failed to read zip")?;
+
+        let trips = read_table::<TripRow>(&mut archive, "trips.txt")?
+            .into_iter()
+            .map(|row| {
+                let trip = StaticTrip {
+                    route_id: row.route_id,
+                    direction_id: row.direction_id,
+                };
+                (row.trip_id, trip)
+            })
+            .collect();
+        let routes = read_table::<RouteRow>(&mut archive, "routes.txt")?
+            .into_iter()
+            .map(|row| row.route_id)
+            .collect();
+        let stops = read_table::<StopRow>(&mut archive, "stops.txt")?
+            .into_iter()
+            .map(|row| row.stop_id)
+            .collect();
+
+        let mut stop_times: HashMap<String, Vec<StaticStopTime>> = HashMap::new();
+        for row in read_table::<StopTimeRow>(&mut archive, "stop_times.txt")? {
+            stop_times
+                .entry(row.trip_id)
+                .or_default()
+                .push(StaticStopTime {
+                    stop_id: row.stop_id,
+                    stop_sequence: row.stop_sequence,
+                    departure_time: row.departure_time.filter(|t| !t.is_empty()),
+                });
+        }
+        for times in stop_times.values_mut() {
+            times.sort_by_key(|st| st.stop_sequence);
+        }
+
+        Ok(Self {
+            trips,
+            routes,
+            stops,
+            stop_times,
+        })
+    }
+
+    pub fn has_trip(&self, trip_id: &str) -> bool {
+        self.trips.contains_key(trip_id)
+    }
+
+    pub fn has_route(&self, route_id: &str) -> bool {
+        self.routes.contains(route_id)
+    }
+
+    pub fn has_stop(&self, stop_id: &str) -> bool {
+        self.stops.contains(stop_id)
+    }
+
+    /// Static `stop_sequence` of the first visit to `stop_id` on `trip_id`.
+    pub fn stop_sequence(&self, trip_id: &str, stop_id: &str) -> Option<u32> {
+        self.stop_times
+            .get(trip_id)?
+            .iter()
+            .find(|st| st.stop_id == stop_id)
+            .map(|st| st.stop_sequence)
+    }
+
+    /// Validates every id in `message` and fills in what the static feed knows.
+    ///
+    /// Stop sequences are matched in order, so trips that visit a stop twice keep both visits.
+    pub fn enrich(&self, message: &mut FeedMessage) -> ValidationReport {
+        let mut report = ValidationReport::default();
+
+        for entity in &mut message.entity {
+            if let Some(vehicle) = &mut entity.vehicle {
+                if let Some(trip) = &mut vehicle.trip {
+                    self.enrich_trip(trip, &mut report);
+                }
+                if let Some(stop_id) = &vehicle.stop_id {
+                    self.check_stop(stop_id, &mut report);
+                }
+                if vehicle.current_stop_sequence.is_some()
+                    && let Some(trip_id) = vehicle.trip.as_ref().and_then(|t| t.trip_id.as_deref())
+                    && let Some(stop_id) = &vehicle.stop_id
+                    && let Some(static_sequence) = self.stop_sequence(trip_id, stop_id)
+                    && vehicle.current_stop_sequence != Some(static_sequence)
+                {
+                    vehicle.current_stop_sequence = Some(static_sequence);
+                    report.corrected_stop_sequences += 1;
+                }
+            }
+
+            if let Some(trip_update) = &mut entity.trip_update {
+                self.enrich_trip(&mut trip_update.trip, &mut report);
+
+                let static_times = trip_update
+                    .trip
+                    .trip_id
+                    .as_deref()
+                    .and_then(|id| self.stop_times.get(id));
+                let mut cursor = 0;
+
+                for update in &mut trip_update.stop_time_update {
+                    let Some(stop_id) = &update.stop_id else {
+                        continue;
+                    };
+                    self.check_stop(stop_id, &mut report);
+
+                    let Some(times) = static_times else {
+                        continue;
+                    };
+                    let Some(offset) = times[cursor..].iter().position(|st| &st.stop_id == stop_id)
+                    else {
+                        continue;
+                    };
+                    let static_sequence = times[cursor + offset].stop_sequence;
+                    cursor += offset + 1;
+
+                    if update.stop_sequence != Some(static_sequence) {
+                        update.stop_sequence = Some(static_sequence);
+                        report.corrected_stop_sequences += 1;
+                    }
+                }
+            }
+        }
+
+        report
+    }
+
+    fn enrich_trip(&self, trip: &mut TripDescriptor, report: &mut ValidationReport) {
+        let Some(trip_id) = &trip.trip_id else {
+            return;
+        };
+        let Some(static_trip) = self.trips.get(trip_id) else {
+            report.unknown_trips.insert(trip_id.clone());
+            return;
+        };
+
+        let mut filled = false;
+        if trip.route_id.is_none() {
+            trip.route_id = Some(static_trip.route_id.clone());
+            filled = true;
+        }
+        if trip.direction_id.is_none() && static_trip.direction_id.is_some() {
+            trip.direction_id = static_trip.direction_id;
+            filled = true;
+        }
+        if trip.start_time.is_none()
+            && let Some(first_departure) = self
+                .stop_times
+                .get(trip_id)
+                .and_then(|times| times.first())
+                .and_then(|st| st.departure_time.clone())
+        {
+            trip.start_time = Some(first_departure);
+            filled = true;
+        }
+        if filled {
+            report.filled_trips += 1;
+        }
+
+        if let Some(route_id) = &trip.route_id
+            && !self.has_route(route_id)
+        {
+            report.unknown_routes.insert(route_id.clone());
+        }
+    }
+
+    fn check_stop(&self, stop_id: &str, report: &mut ValidationReport) {
+        if !self.has_stop(stop_id) {
+            report.unknown_stops.insert(stop_id.to_string());
+        }
+    }
+}
+
+/// Loads the static GTFS configured on each feed's agency; feeds without one are skipped.
+///
+/// Feeds whose zip cannot be read are logged and skipped rather than failing the rest.
+pub fn load_for_feeds(feeds: &[FeedId]) -> HashMap<FeedId, StaticGtfs> {
+    let paths: Vec<_> = {
+        let registry = agency::registry();
+        feeds
+            .iter()
+            .filter_map(|&feed| Some((feed, registry.get(feed)?.static_gtfs_path.clone()?)))
+            .collect()
+    };
+
+    paths
+        .into_iter()
+        .filter_map(|(feed, path)| match StaticGtfs::from_zip(&path) {
+            Ok(gtfs) => Some((feed, gtfs)),
+            Err(e) => {
+                eprintln!("Failed to load static GTFS for {}: {:#}", feed, e);
+                None
+            }
+        })
+        .collect()
+}
+
+/// Reads a GTFS table, allowing the zip to nest files inside a single directory.
+fn read_table<T: for<'de> Deserialize<'de>>(
+    archive: &mut ZipArchive<impl Read + Seek>,
+    name: &str,
+) -> Result<Vec<T>> {
+    let index = (0..archive.len())
+        .find(|&i| {
+            archive
+                .name_for_index(i)
+                .is_some_and(|entry| entry == name || entry.ends_with(&format!("/{}", name)))
+        })
+        .with_context(|| format!("missing {}", name))?;
+
+    let mut text = String::new();
+    archive
+        .by_index(index)?
+        .read_to_string(&mut text)
+        .with_context(|| format!("failed to read {}", name))?;
+
+    csv::Reader::from_reader(text.as_bytes())
+        .deserialize()
+        .collect::<Result<Vec<T>, _>>()
+        .with_context(|| format!("failed to parse {}", name))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::gtfs::{FeedEntity, StopTimeUpdate, TripUpdate, VehiclePosition};
+    use std::io::{Cursor, Write};
+    use zip::ZipWriter;
+    use zip::write::SimpleFileOptions;
+
+    /// Loop route L1: trip T1 starts and ends at stop A.
+    fn loop_gtfs() -> StaticGtfs {
+        let tables = [
+            ("routes.txt", "route_id\nL1\n"),
+            ("trips.txt", "route_id,trip_id,direction_id\nL1,T1,0\n"),
+            ("stops.txt", "stop_id\nA\nB\nC\n"),
+            (
+                "stop_times.txt",
+                "trip_id,stop_id,stop_sequence,departure_time\n\
+                 T1,B,2,08:10:00\n\
+                 T1,A,1,08:00:00\n\
+                 T1,C,3,08:20:00\n\
+                 T1,A,4,\n",
+            ),
+        ];
+
+        let mut zip = ZipWriter::new(Cursor::new(Vec::new()));
+        for (name, text) in tables {
+            zip.start_file(format!("gtfs/{}", name), SimpleFileOptions::default())
+                .unwrap();
+            zip.write_all(text.as_bytes()).unwrap();
+        }
+        StaticGtfs::from_reader(zip.finish().unwrap()).unwrap()
+    }
+
+    fn trip(trip_id: &str) -> TripDescriptor {
+        TripDescriptor {
+            trip_id: Some(trip_id.to_string()),
+            ..Default::default()
+        }
+    }
+
+    fn trip_update(trip_id: &str, stops: &[(&str, Option<u32>)]) -> FeedEntity {
+        FeedEntity {
+            id: trip_id.to_string(),
+            trip_update: Some(TripUpdate {
+                trip: trip(trip_id),
+                stop_time_update: stops
+                    .iter()
+                    .map(|&(stop_id, stop_sequence)| StopTimeUpdate {
+                        stop_id: Some(stop_id.to_string()),
+                        stop_sequence,
+                        ..Default::default()
+                    })
+                    .collect(),
+                ..Default::default()
+            }),
+            ..Default::default()
+        }
+    }
+
+    fn sequences(entity: &FeedEntity) -> Vec<Option<u32>> {
+        entity
+            .trip_update
+            .as_ref()
+            .unwrap()
+            .stop_time_update
+            .iter()
+            .map(|update| update.stop_sequence)
+            .collect()
+    }
+
+    #[test]
+    fn corrects_stop_sequences_on_a_loop_and_fills_the_trip() {
+        let gtfs = loop_gtfs();
+        let mut message = FeedMessage {
+            entity: vec![trip_update(
+                "T1",
+                &[
+                    ("A", Some(10)),
+                    ("B", None),
+                    ("Z", Some(7)),
+                    ("C", Some(3)),
+                    ("A", Some(12)),
+                ],
+            )],
+            ..Default::default()
+        };
+
+        let report = gtfs.enrich(&mut message);
+
+        let entity = &message.entity[0];
+        assert_eq!(
+            sequences(entity),
+            [Some(1), Some(2), Some(7), Some(3), Some(4)]
+        );
+        let trip = &entity.trip_update.as_ref().unwrap().trip;
+        assert_eq!(trip.route_id.as_deref(), Some("L1"));
+        assert_eq!(trip.direction_id, Some(0));
+        assert_eq!(trip.start_time.as_deref(), Some("08:00:00"));
+
+        assert_eq!(report.corrected_stop_sequences, 3);
+        assert_eq!(report.filled_trips, 1);
+        assert_eq!(report.unknown_stops, BTreeSet::from(["Z".to_string()]));
+        assert!(report.unknown_trips.is_empty() && report.unknown_routes.is_empty());
+    }
+
+    #[test]
+    fn partial_trip_matches_the_later_visit() {
+        let gtfs = loop_gtfs();
+        // The first visit to A has passed, so the feed starts at C.
+        let mut message = FeedMessage {
+            entity: vec![trip_update("T1", &[("C", None), ("A", None)])],
+            ..Default::default()
+        };
+
+        gtfs.enrich(&mut message);
+
+        assert_eq!(sequences(&message.entity[0]), [Some(3), Some(4)]);
+    }
+
+    #[test]
+    fn reports_unknown_ids_and_keeps_realtime_values() {
+        let gtfs = loop_gtfs();
+        let mut message = FeedMessage {
+            entity: vec![
+                FeedEntity {
+                    id: "bus".to_string(),
+                    vehicle: Some(VehiclePosition {
+                        trip: Some(TripDescriptor {
+                            route_id: Some("L2".to_string()),
+                            direction_id: Some(1),
+                            start_time: Some("08:05:00".to_string()),
+                            ..trip("T1")
+                        }),
+                        stop_id: Some("C".to_string()),
+                        current_stop_sequence: Some(9),
+                        ..Default::default()
+                    }),
+                    ..Default::default()
+                },
+                trip_update("T9", &[("A", Some(1))]),
+            ],
+            ..Default::default()
+        };
+
+        let report = gtfs.enrich(&mut message);
+
+        let vehicle = message.entity[0].vehicle.as_ref().unwrap();
+        let trip = vehicle.trip.as_ref().unwrap();
+        assert_eq!(trip.route_id.as_deref(), Some("L2"));
+        assert_eq!(trip.direction_id, Some(1));
+        assert_eq!(trip.start_time.as_deref(), Some("08:05:00"));
+        assert_eq!(vehicle.current_stop_sequence, Some(3));
+
+        assert_eq!(report.corrected_stop_sequences, 1);
+        assert_eq!(report.filled_trips, 0);
+        assert_eq!(report.unknown_trips, BTreeSet::from(["T9".to_string()]));
+        assert_eq!(report.unknown_routes, BTreeSet::from(["L2".to_string()]));
+        assert!(!report.is_clean());
+    }
+}
@@ -0,0 +1,130 @@
+use crate::agency::FeedId;
+use crate::diff::{as_differential, diff_feeds};
+use crate::gtfs::{FeedEntity, FeedMessage};
+use crate::poller::{FeedKind, FeedSnapshot, Poller};
+use anyhow::Result;
+use serde::Deserialize;
+use std::collections::HashSet;
+use std::sync::Arc;
+use tokio::sync::watch;
+
+/// What a streaming client asked for, from the query string of `/{feed}/stream` or
+/// `/{feed}/events`.
+#[derive(Debug, Default, Deserialize)]
+pub struct StreamQuery {
+    /// `vehicle_positions`, `trip_updates` or `combined` (default).
+    pub kind: Option<String>,
+    /// `full` (default) or `differential`.
+    pub incrementality: Option<String>,
+    /// Comma-separated route ids; empty means every route.
+    pub routes: Option<String>,
+    /// `pb` (default) or `json`; SSE always sends JSON.
+    pub format: Option<String>,
+}
+
+/// Yields the messages of one feed as the poller publishes them, filtered to the client's
+/// routes and, in differential mode, diffed against what this client was last sent.
+pub struct Subscription {
+    rx: watch::Receiver<Arc<FeedSnapshot>>,
+    kind: FeedKind,
+    differential: bool,
+    routes: HashSet<String>,
+    last_source: Option<Arc<FeedMessage>>,
+    last_sent: Option<FeedMessage>,
+}
+
+impl Subscription {
+    /// `Err` describes a bad query; `Ok(None)` means the poller does not serve `feed`.
+    pub fn new(poller: &Poller, feed: FeedId, query: &StreamQuery) -> Result<Option<Self>> {
+        let kind = match query.kind.as_deref() {
+            Some("combined") | None => FeedKind::Combined,
+            Some("vehicle_positions") => FeedKind::VehiclePositions,
+            Some("trip_updates") => FeedKind::TripUpdates,
+            Some(other) => anyhow::bail!("unsupported kind {}", other),
+        };
+        let differential = match query.incrementality.as_deref() {
+            Some("full") | None => false,
+            Some("differential") => true,
+            Some(other) => anyhow::bail!("unsupported incrementality {}", other),
+        };
+        let routes = query
+            .routes
+            .iter()
+            .flat_map(|routes| routes.split(','))
+            .map(str::trim)
+            .filter(|route| !route.is_empty())
+            .map(str::to_string)
+            .collect();
+
+        Ok(poller.subscribe(feed).map(|rx| Self {
+            rx,
+            kind,
+            differential,
+            routes,
+            last_source: None,
+            last_sent: None,
+        }))
+    }
+
+    /// The next message to send, waiting for the poller if the current one was already sent.
+    /// `None` once the poller is gone.
+    pub async fn next(&mut self) -> Option<FeedMessage> {
+        loop {
+            let source = self.rx.borrow_and_update().message(self.kind);
+            let fresh = source
+                .as_ref()
+                .is_some_and(|s| !self.last_source.as_ref().is_some_and(|l| Arc::ptr_eq(l, s)));
+
+            if let (true, Some(source)) = (fresh, source) {
+                let filtered = filter_routes(&source, &self.routes);
+                let message = match (&self.last_sent, self.differential) {
+                    (_, false) => filtered.clone(),
+                    (Some(previous), true) => diff_feeds(previous, &filtered),
+                    (None, true) => as_differential(&filtered),
+                };
+                self.last_source = Some(source);
+                self.last_sent = Some(filtered);
+                return Some(message);
+            }
+
+            self.rx.changed().await.ok()?;
+        }
+    }
+
+    pub fn kind_name(&self) -> &'static str {
+        match self.kind {
+            FeedKind::VehiclePositions => "vehicle_positions",
+            FeedKind::TripUpdates => "trip_updates",
+            FeedKind::Combined => "combined",
+        }
+    }
+}
+
+/// Keeps only the entities serving one of `routes`; an empty set keeps everything.
+pub fn filter_routes(message: &FeedMessage, routes: &HashSet<String>) -> FeedMessage {
+    if routes.is_empty() {
+        return message.clone();
+    }
+    FeedMessage {
+        header: message.header.clone(),
+        entity: message
+            .entity
+            .iter()
+            .filter(|entity| entity_route(entity).is_some_and(|route| routes.contains(route)))
+            .cloned()
+            .collect(),
+    }
+}
+
+fn entity_route(entity: &FeedEntity) -> Option<&str> {
+    let from_vehicle = entity
+        .vehicle
+        .as_ref()
+        .and_then(|v| v.trip.as_ref())
+        .and_then(|t| t.route_id.as_deref());
+    let from_trip_update = entity
+        .trip_update
+        .as_ref()
+        .and_then(|tu| tu.trip.route_id.as_deref());
+    from_vehicle.or(from_trip_update)
+}
@@ -0,0 +1,267 @@
+use reqwest::StatusCode;
+use serde::{Deserialize, Serialize};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::Notify;
+
+/// Client-side limits on how hard one tenant's gateway is hit.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ThrottleConfig {
+    /// Sustained request rate; `0` disables rate limiting.
+    pub requests_per_second: f64,
+    /// Requests that may be sent back to back before the rate applies.
+    pub burst: u32,
+    /// Concurrent requests allowed before the gateway has given any feedback.
+    pub initial_concurrency: usize,
+    pub min_concurrency: usize,
+    pub max_concurrency: usize,
+    /// Responses slower than this count as congestion, like a 429 or 503.
+    pub slow_response_ms: u64,
+}
+
+impl Default for ThrottleConfig {
+    fn default() -> Self {
+        Self {
+            requests_per_second: 20.0,
+            burst: 20,
+            initial_concurrency: 4,
+            min_concurrency: 1,
+            max_concurrency: 16,
+            slow_response_ms: 3_000,
+        }
+    }
+}
+
+/// Rate limiter and concurrency limiter for one tenant, shared by every clone of its client.
+#[derive(Debug)]
+pub struct Throttle {
+    bucket: TokenBucket,
+    concurrency: Arc<AdaptiveConcurrency>,
+}
+
+impl Throttle {
+    pub fn new(config: &ThrottleConfig) -> Self {
+        Self {
+            bucket: TokenBucket::new(config.requests_per_second, config.burst),
+            concurrency: Arc::new(AdaptiveConcurrency::new(config)),
+        }
+    }
+
+    /// Waits for both a rate token and a concurrency slot.
+    pub async fn acquire(&self) -> ConcurrencyPermit {
+        let mut permit = self.concurrency.acquire().await;
+        self.bucket.acquire().await;
+        // Latency is measured from the send, not from the wait for a token.
+        permit.started = Instant::now();
+        permit
+    }
+
+    pub fn concurrency_limit(&self) -> usize {
+        self.concurrency.limit()
+    }
+}
+
+#[derive(Debug)]
+struct BucketState {
+    tokens: f64,
+    refilled_at: Instant,
+}
+
+/// Classic token bucket: `rate` tokens a second, holding at most `burst`.
+#[derive(Debug)]
+pub struct TokenBucket {
+    rate: f64,
+    burst: f64,
+    state: Mutex<BucketState>,
+}
+
+impl TokenBucket {
+    pub fn new(rate: f64, burst: u32) -> Self {
+        let burst = burst.max(1) as f64;
+        Self {
+            rate,
+            burst,
+            state: Mutex::new(BucketState {
+                tokens: burst,
+                refilled_at: Instant::now(),
+            }),
+        }
+    }
+
+    pub async fn acquire(&self) {
+        if self.rate <= 0.0 {
+            return;
+        }
+        loop {
+            let wait = {
+                let mut state = self.state.lock().unwrap();
+                let now = Instant::now();
+                let elapsed = now.duration_since(state.refilled_at).as_secs_f64();
+                state.tokens = (state.tokens + elapsed * self.rate).min(self.burst);
+                state.refilled_at = now;
+
+                if state.tokens >= 1.0 {
+                    state.tokens -= 1.0;
+                    return;
+                }
+                Duration::from_secs_f64((1.0 - state.tokens) / self.rate)
+            };
+            tokio::time::sleep(wait).await;
+        }
+    }
+}
+
+#[derive(Debug)]
+struct ConcurrencyState {
+    limit: f64,
+    in_flight: usize,
+    decreased_at: Option<Instant>,
+}
+
+/// AIMD concurrency limit: grows by one slot per window of healthy responses and halves on
+/// a 429, 503 or slow response.
+///
+/// Only one decrease applies per window: congestion reported by requests that were already
+/// in flight when the limit last dropped is ignored, so a burst of 429s halves it once.
+#[derive(Debug)]
+pub struct AdaptiveConcurrency {
+    min: f64,
+    max: f64,
+    slow_response: Duration,
+    state: Mutex<ConcurrencyState>,
+    released: Notify,
+}
+
+impl AdaptiveConcurrency {
+    pub fn new(config: &ThrottleConfig) -> Self {
+        let min = config.min_concurrency.max(1) as f64;
+        let max = (config.max_concurrency as f64).max(min);
+        Self {
+            min,
+            max,
+            slow_response: Duration::from_millis(config.slow_response_ms),
+            state: Mutex::new(ConcurrencyState {
+                limit: (config.initial_concurrency as f64).clamp(min, max),
+                in_flight: 0,
+                decreased_at: None,
+            }),
+            released: Notify::new(),
+        }
+    }
+
+    pub fn limit(&self) -> usize {
+        self.state.lock().unwrap().limit as usize
+    }
+
+    pub async fn acquire(self: &Arc<Self>) -> ConcurrencyPermit {
+        loop {
+            let released = self.released.notified();
+            {
+                let mut state = self.state.lock().unwrap();
+                if state.in_flight < state.limit as usize {
+                    state.in_flight += 1;
+                    if state.in_flight < state.limit as usize {
+                        self.released.notify_one();
+                    }
+                    return ConcurrencyPermit {
+                        limiter: self.clone(),
+                        started: Instant::now(),
+                    };
+                }
+            }
+            released.await;
+        }
+    }
+
+    fn record(&self, status: StatusCode, started: Instant) {
+        let congested = matches!(
+            status,
+            StatusCode::TOO_MANY_REQUESTS | StatusCode::SERVICE_UNAVAILABLE
+        ) || started.elapsed() > self.slow_response;
+        self.adjust(congested, started);
+    }
+
+    /// Feeds back the outcome of a request sent at `started`.
+    fn adjust(&self, congested: bool, started: Instant) {
+        let mut state = self.state.lock().unwrap();
+        if !congested {
+            state.limit = (state.limit + 1.0 / state.limit).min(self.max);
+            return;
+        }
+        if state.decreased_at.is_some_and(|at| started < at) {
+            return;
+        }
+        state.limit = (state.limit / 2.0).max(self.min);
+        state.decreased_at = Some(Instant::now());
+    }
+}
+
+/// A concurrency slot, returned when dropped.
+#[derive(Debug)]
+pub struct ConcurrencyPermit {
+    limiter: Arc<AdaptiveConcurrency>,
+    started: Instant,
+}
+
+impl ConcurrencyPermit {
+    /// Feeds the response back into the limit.
+    pub fn record(&self, status: StatusCode) {
+        self.limiter.record(status, self.started);
+    }
+
+    /// A request that got no response at all is treated as congestion.
+    pub fn record_failure(&self) {
+        self.limiter.adjust(true, self.started);
+    }
+}
+
+impl Drop for ConcurrencyPermit {
+    fn drop(&mut self) {
+        self.limiter.state.lock().unwrap().in_flight -= 1;
+        self.limiter.released.notify_one();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn limiter(initial: usize) -> Arc<AdaptiveConcurrency> {
+        Arc::new(AdaptiveConcurrency::new(&ThrottleConfig {
+            initial_concurrency: initial,
+            max_concurrency: 16,
+            ..ThrottleConfig::default()
+        }))
+    }
+
+    #[tokio::test]
+    async fn burst_of_429s_halves_the_limit_once() {
+        let limiter = limiter(16);
+        let mut permits = Vec::new();
+        for _ in 0..16 {
+            permits.push(limiter.acquire().await);
+        }
+        for permit in &permits {
+            permit.record(StatusCode::TOO_MANY_REQUESTS);
+        }
+        assert_eq!(limiter.limit(), 8);
+
+        drop(permits);
+        let permit = limiter.acquire().await;
+        permit.record(StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(limiter.limit(), 4);
+    }
+
+    #[tokio::test]
+    async fn healthy_responses_grow_the_limit_by_one_per_window() {
+        let limiter = limiter(4);
+        for _ in 0..4 {
+            limiter.acquire().await.record(StatusCode::OK);
+        }
+        assert_eq!(limiter.limit(), 4);
+        // Each response adds 1/limit, so four leave it at 4.92 and the fifth passes 5.
+        limiter.acquire().await.record(StatusCode::OK);
+        assert_eq!(limiter.limit(), 5);
+    }
+}
@@ -0,0 +1,208 @@
+use crate::agency::FeedId;
+use crate::model::{TripUpdateResponse, VehicleContent};
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::time::{Duration, Instant};
+
+/// How long a downloaded trip timetable is reused.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct TimetableCacheConfig {
+    /// Age after which a timetable is downloaded again even if its vehicle has not reached a
+    /// new stop; `0` disables the cache.
+    pub max_age_secs: u64,
+}
+
+impl Default for TimetableCacheConfig {
+    fn default() -> Self {
+        Self { max_age_secs: 120 }
+    }
+}
+
+#[derive(Debug)]
+struct CachedTimetable {
+    feed: FeedId,
+    vehicle_id: String,
+    stop_id: Option<String>,
+    fetched_at: Instant,
+    response: TripUpdateResponse,
+}
+
+/// Last timetable downloaded for each active trip, keyed by full trip id.
+///
+/// A trip is downloaded again when it is new, when its vehicle has moved on to another
+/// stop (or been swapped for another vehicle), or once the timetable is older than
+/// `max_age_secs`.
+#[derive(Debug)]
+pub struct TimetableCache {
+    max_age: Duration,
+    trips: HashMap<String, CachedTimetable>,
+}
+
+impl TimetableCache {
+    pub fn new(config: &TimetableCacheConfig) -> Self {
+        Self {
+            max_age: Duration::from_secs(config.max_age_secs),
+            trips: HashMap::new(),
+        }
+    }
+
+    /// The cached timetable of `trip_id`, if it is still good for `vehicle` at `now`.
+    pub fn fresh(
+        &self,
+        trip_id: &str,
+        vehicle: &VehicleContent,
+        now: Instant,
+    ) -> Option<&TripUpdateResponse> {
+        let cached = self.trips.get(trip_id)?;
+        let fresh = now.saturating_duration_since(cached.fetched_at) < self.max_age
+            && cached.vehicle_id == vehicle.id
+            && cached.stop_id.as_deref() == stop_id(vehicle);
+        fresh.then_some(&cached.response)
+    }
+
+    /// The cached timetable of `trip_id` however old, as a fallback when a refresh fails.
+    pub fn last(&self, trip_id: &str) -> Option<&TripUpdateResponse> {
+        self.trips.get(trip_id).map(|cached| &cached.response)
+    }
+
+    pub fn insert(
+        &mut self,
+        feed: FeedId,
+        trip_id: String,
+        vehicle: &VehicleContent,
+        response: TripUpdateResponse,
+        fetched_at: Instant,
+    ) {
+        if self.max_age.is_zero() {
+            return;
+        }
+        self.trips.insert(
+            trip_id,
+            CachedTimetable {
+                feed,
+                vehicle_id: vehicle.id.clone(),
+                stop_id: stop_id(vehicle).map(str::to_string),
+                fetched_at,
+                response,
+            },
+        );
+    }
+
+    /// Forgets the trips of `feeds` that are not in `active`, i.e. whose vehicles have left
+    /// the snapshot. Trips of other feeds are kept. Returns how many were evicted.
+    pub fn evict_inactive(&mut self, feeds: &HashSet<FeedId>, active: &HashSet<&str>) -> usize {
+        let before = self.trips.len();
+        self.trips.retain(|trip_id, cached| {
+            !feeds.contains(&cached.feed) || active.contains(trip_id.as_str())
+        });
+        before - self.trips.len()
+    }
+
+    pub fn len(&self) -> usize {
+        self.trips.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.trips.is_empty()
+    }
+}
+
+fn stop_id(vehicle: &VehicleContent) -> Option<&str> {
+    vehicle.stop.as_ref().map(|stop| stop.id.as_str())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn vehicle(id: &str, stop: Option<&str>) -> VehicleContent {
+        let stop = stop.map(|id| serde_json::json!({"id": id, "feedId": "DART"}));
+        serde_json::from_value(serde_json::json!({
+            "id": id,
+            "coordinate": {"lat": 33.2, "lng": -97.1},
+            "stop": stop,
+        }))
+        .unwrap()
+    }
+
+    fn timetable() -> TripUpdateResponse {
+        serde_json::from_str(r#"{"state":"REALTIME","entries":[]}"#).unwrap()
+    }
+
+    fn cache(max_age_secs: u64) -> TimetableCache {
+        TimetableCache::new(&TimetableCacheConfig { max_age_secs })
+    }
+
+    #[test]
+    fn expires_after_max_age() {
+        let mut cache = cache(120);
+        let bus = vehicle("DART-42", Some("DART:S1"));
+        let fetched_at = Instant::now();
+        cache.insert(FeedId::DART, "DART:1".into(), &bus, timetable(), fetched_at);
+
+        let later = |secs| fetched_at + Duration::from_secs(secs);
+        assert!(cache.fresh("DART:1", &bus, later(119)).is_some());
+        assert!(cache.fresh("DART:1", &bus, later(120)).is_none());
+        // Still available as a fallback.
+        assert!(cache.last("DART:1").is_some());
+    }
+
+    #[test]
+    fn new_stop_or_vehicle_invalidates() {
+        let mut cache = cache(120);
+        let now = Instant::now();
+        cache.insert(
+            FeedId::DART,
+            "DART:1".into(),
+            &vehicle("DART-42", Some("DART:S1")),
+            timetable(),
+            now,
+        );
+
+        let fresh = |id, stop| cache.fresh("DART:1", &vehicle(id, stop), now).is_some();
+        assert!(fresh("DART-42", Some("DART:S1")));
+        assert!(!fresh("DART-42", Some("DART:S2")));
+        assert!(!fresh("DART-42", None));
+        assert!(!fresh("DART-43", Some("DART:S1")));
+    }
+
+    #[test]
+    fn zero_max_age_disables_the_cache() {
+        let mut cache = cache(0);
+        let bus = vehicle("DART-42", None);
+        cache.insert(
+            FeedId::DART,
+            "DART:1".into(),
+            &bus,
+            timetable(),
+            Instant::now(),
+        );
+
+        assert!(cache.is_empty());
+    }
+
+    #[test]
+    fn evicts_inactive_trips_of_the_polled_feeds_only() {
+        let mut cache = cache(120);
+        let now = Instant::now();
+        for (feed, trip_id) in [
+            (FeedId::DART, "DART:1"),
+            (FeedId::DART, "DART:2"),
+            (FeedId::CCRTA, "CCRTA:3"),
+        ] {
+            let bus = vehicle(&format!("{}-bus", trip_id), None);
+            cache.insert(feed, trip_id.to_string(), &bus, timetable(), now);
+        }
+
+        let evicted =
+            cache.evict_inactive(&HashSet::from([FeedId::DART]), &HashSet::from(["DART:1"]));
+
+        assert_eq!(evicted, 1);
+        assert_eq!(cache.len(), 2);
+        assert!(cache.last("DART:1").is_some());
+        assert!(cache.last("DART:2").is_none());
+        // CCRTA was not polled this cycle, so its trips are kept.
+        assert!(cache.last("CCRTA:3").is_some());
+    }
+}
@@ -0,0 +1,272 @@
+use anyhow::{Context, Result};
+use futures::future::BoxFuture;
+use reqwest::header::{HeaderMap, HeaderName, HeaderValue};
+use reqwest::{Client, Method, StatusCode, Url};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, VecDeque};
+use std::sync::Mutex;
+use std::time::Duration;
+
+/// A request as handed to an [`HttpTransport`].
+#[derive(Debug, Clone)]
+pub struct HttpRequest {
+    pub method: Method,
+    pub url: String,
+    pub headers: Vec<(String, String)>,
+    pub body: Option<Vec<u8>>,
+}
+
+impl HttpRequest {
+    pub fn new(method: Method, url: impl Into<String>) -> Self {
+        Self {
+            method,
+            url: url.into(),
+            headers: Vec::new(),
+            body: None,
+        }
+    }
+
+    pub fn get(url: impl Into<String>) -> Self {
+        Self::new(Method::GET, url)
+    }
+
+    pub fn post(url: impl Into<String>) -> Self {
+        Self::new(Method::POST, url)
+    }
+
+    pub fn header(mut self, name: impl Into<String>, value: impl Into<String>) -> Self {
+        self.headers.push((name.into(), value.into()));
+        self
+    }
+
+    /// Sets a JSON body; the `Content-Type` header is left to the caller.
+    pub fn json<T: Serialize>(mut self, body: &T) -> Result<Self> {
+        self.body = Some(serde_json::to_vec(body).context("failed to encode request body")?);
+        Ok(self)
+    }
+
+    /// Path part of the URL, e.g. `/api-gateway/v1/appinstance`.
+    pub fn path(&self) -> Result<String> {
+        let url = Url::parse(&self.url).with_context(|| format!("invalid URL {}", self.url))?;
+        Ok(url.path().to_string())
+    }
+}
+
+/// A response with its body already read.
+#[derive(Debug, Clone)]
+pub struct HttpResponse {
+    pub status: StatusCode,
+    pub headers: HeaderMap,
+    pub body: Vec<u8>,
+}
+
+impl HttpResponse {
+    pub fn new(status: StatusCode, body: impl Into<Vec<u8>>) -> Self {
+        Self {
+            status,
+            headers: HeaderMap::new(),
+            body: body.into(),
+        }
+    }
+
+    /// A response carrying `body` serialized as JSON.
+    pub fn json_body<T: Serialize>(status: StatusCode, body: &T) -> Result<Self> {
+        let body = serde_json::to_vec(body).context("failed to encode response body")?;
+        Ok(Self::new(status, body).with_header("Content-Type", "application/json"))
+    }
+
+    /// Adds a header, ignoring names or values that are not valid HTTP.
+    pub fn with_header(mut self, name: &str, value: &str) -> Self {
+        if let (Ok(name), Ok(value)) = (
+            HeaderName::from_bytes(name.as_bytes()),
+            HeaderValue::from_str(value),
+        ) {
+            self.headers.append(name, value);
+        }
+        self
+    }
+
+    pub fn status(&self) -> StatusCode {
+        self.status
+    }
+
+    pub fn headers(&self) -> &HeaderMap {
+        &self.headers
+    }
+
+    /// The body as text, with invalid UTF-8 replaced.
+    pub fn text(&self) -> String {
+        String::from_utf8_lossy(&self.body).into_owned()
+    }
+
+    pub fn json<T: DeserializeOwned>(&self) -> Result<T> {
+        Ok(serde_json::from_slice(&self.body)?)
+    }
+}
+
+/// Sends requests for [`UnwireClient`] and [`Authenticator`].
+///
+/// [`UnwireClient`]: crate::client::UnwireClient
+/// [`Authenticator`]: crate::auth::Authenticator
+pub trait HttpTransport: Send + Sync {
+    /// Sends `request` and reads the whole response. Only failures to get a response are
+    /// errors; any status is returned as is.
+    fn send(&self, request: HttpRequest) -> BoxFuture<'_, Result<HttpResponse>>;
+}
+
+/// How long a gateway request may take before it is abandoned.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct TimeoutConfig {
+    /// Limit for the whole request, from connecting until the body has been read.
+    pub request_secs: u64,
+    /// Limit for establishing the connection.
+    pub connect_secs: u64,
+}
+
+impl Default for TimeoutConfig {
+    fn default() -> Self {
+        Self {
+            request_secs: 30,
+            connect_secs: 10,
+        }
+    }
+}
+
+/// Sends requests over the network with `reqwest`.
+///
+/// Errors keep their [`reqwest::Error`], so retries can tell transient ones (timeouts
+/// included) apart.
+#[derive(Debug, Clone)]
+pub struct ReqwestTransport {
+    client: Client,
+}
+
+impl ReqwestTransport {
+    pub fn new(client: Client) -> Self {
+        Self { client }
+    }
+
+    /// A transport whose requests fail once they exceed `timeouts`.
+    pub fn with_timeouts(timeouts: &TimeoutConfig) -> Result<Self> {
+        let client = Client::builder()
+            .timeout(Duration::from_secs(timeouts.request_secs))
+            .connect_timeout(Duration::from_secs(timeouts.connect_secs))
+            .build()
+            .context("failed to build HTTP client")?;
+        Ok(Self::new(client))
+    }
+}
+
+impl HttpTransport for ReqwestTransport {
+    fn send(&self, request: HttpRequest) -> BoxFuture<'_, Result<HttpResponse>> {
+        Box::pin(async move {
+            let mut req = self.client.request(request.method, &request.url);
+            for (name, value) in request.headers {
+                req = req.header(name, value);
+            }
+            if let Some(body) = request.body {
+                req = req.body(body);
+            }
+
+            let resp = req.send().await?;
+            let status = resp.status();
+            let headers = resp.headers().clone();
+            let body = resp.bytes().await?.to_vec();
+            Ok(HttpResponse {
+                status,
+                headers,
+                body,
+            })
+        })
+    }
+}
+
+/// Serves canned responses from memory, so the client can run without the gateway.
+///
+/// Responses are queued per method and URL path. They are served in order, and the last
+/// one queued for a route keeps being served once the others are used up. A request with
+/// nothing queued fails. Every request is recorded for inspection.
+#[derive(Debug, Default)]
+pub struct InMemoryTransport {
+    routes: Mutex<HashMap<(Method, String), VecDeque<HttpResponse>>>,
+    requests: Mutex<Vec<HttpRequest>>,
+}
+
+impl InMemoryTransport {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Queues `response` for requests to `path`, e.g. `/api-gateway/v1/appinstance`.
+    pub fn respond(&self, method: Method, path: impl Into<String>, response: HttpResponse) {
+        self.routes
+            .lock()
+            .unwrap()
+            .entry((method, path.into()))
+            .or_default()
+            .push_back(response);
+    }
+
+    /// Every request sent so far, oldest first.
+    pub fn requests(&self) -> Vec<HttpRequest> {
+        self.requests.lock().unwrap().clone()
+    }
+
+    fn next_response(&self, request: &HttpRequest) -> Result<HttpResponse> {
+        let path = request.path()?;
+        let mut routes = self.routes.lock().unwrap();
+        let queue = routes
+            .get_mut(&(request.method.clone(), path))
+            .filter(|queue| !queue.is_empty())
+            .with_context(|| {
+                format!("no canned response for {} {}", request.method, request.url)
+            })?;
+
+        let response = if queue.len() > 1 {
+            queue.pop_front()
+        } else {
+            queue.front().cloned()
+        };
+        Ok(response.expect("queue is not empty"))
+    }
+}
+
+impl HttpTransport for InMemoryTransport {
+    fn send(&self, request: HttpRequest) -> BoxFuture<'_, Result<HttpResponse>> {
+        let response = self.next_response(&request);
+        self.requests.lock().unwrap().push(request);
+        Box::pin(async move { response })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::net::TcpListener;
+
+    #[tokio::test]
+    async fn silent_gateway_times_out() {
+        // Accepts connections but never answers.
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/v1/appinstance", listener.local_addr().unwrap());
+        let server = tokio::spawn(async move {
+            let mut connections = Vec::new();
+            while let Ok((stream, _)) = listener.accept().await {
+                connections.push(stream);
+            }
+        });
+
+        let transport = ReqwestTransport::with_timeouts(&TimeoutConfig {
+            request_secs: 1,
+            connect_secs: 1,
+        })
+        .unwrap();
+        let error = transport.send(HttpRequest::get(url)).await.unwrap_err();
+        server.abort();
+
+        let error = error.downcast_ref::<reqwest::Error>().unwrap();
+        assert!(error.is_timeout(), "{error}");
+    }
+}